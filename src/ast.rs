@@ -1,47 +1,335 @@
+use crate::error::Span;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Type {
+    Char,
+    SChar,
+    UChar,
+    Short,
+    UShort,
     Int,
+    UInt,
+    Long,
+    ULong,
+    LongLong,
+    ULongLong,
+    Float,
+    Double,
+    Void,
+    Pointer(Box<Type>),
+    Array(Box<Type>, Length),
+    Struct(Identifier),
+    Function(Box<Type>, Vec<Type>, bool), // return type, parameter types and variadicity
+    Qualified(Box<Type>, Qualifiers),     // never nested nor empty
+}
+
+// the number of elements of an array, kept as written until lowering computes it since it may
+// use sizeof
+#[derive(Debug, Clone)]
+pub enum Length {
+    Constant(usize),
+    Expression(Box<Expression>),
+}
+
+// only lowered types are ever compared
+impl PartialEq for Length {
+    fn eq(&self, other: &Self) -> bool {
+        matches!((self, other), (Length::Constant(l1), Length::Constant(l2)) if l1 == l2)
+    }
+}
+
+impl Eq for Length {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Qualifiers {
+    pub constant: bool,
+    // every access is generated exactly as written anyway, no load or store is ever
+    // elided or merged, so volatile only has to be kept along with the type
+    pub volatile: bool,
+}
+
+impl Qualifiers {
+    pub fn is_empty(&self) -> bool {
+        !self.constant && !self.volatile
+    }
+
+    pub fn union(self, other: Qualifiers) -> Qualifiers {
+        Qualifiers {
+            constant: self.constant || other.constant,
+            volatile: self.volatile || other.volatile,
+        }
+    }
+
+    pub fn contains(&self, other: Qualifiers) -> bool {
+        self.union(other) == *self
+    }
+}
+
+// the structure behind va_list, laid out by the System V ABI
+pub const VA_LIST_TAG: &str = "__va_list_tag";
+
+impl Type {
+    pub fn qualify(self, qualifiers: Qualifiers) -> Type {
+        match self {
+            _ if qualifiers.is_empty() => self,
+            Type::Qualified(t, q) => Type::Qualified(t, q.union(qualifiers)),
+            t => Type::Qualified(Box::new(t), qualifiers),
+        }
+    }
+
+    pub fn qualifiers(&self) -> Qualifiers {
+        match self {
+            Type::Qualified(_, q) => *q,
+            _ => Qualifiers::default(),
+        }
+    }
+
+    // the type without its qualifiers, which only matter to objects and not to their values,
+    // so every other query looks through them
+    pub fn unqualified(&self) -> &Type {
+        match self {
+            Type::Qualified(t, _) => t,
+            t => t,
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.rank().is_some()
+    }
+
+    pub fn is_unsigned(&self) -> bool {
+        matches!(
+            self.unqualified(),
+            Type::UChar | Type::UShort | Type::UInt | Type::ULong | Type::ULongLong
+        )
+    }
+
+    // integer conversion rank, None for non integer types
+    pub fn rank(&self) -> Option<usize> {
+        match self.unqualified() {
+            Type::Char | Type::SChar | Type::UChar => Some(1),
+            Type::Short | Type::UShort => Some(2),
+            Type::Int | Type::UInt => Some(3),
+            Type::Long | Type::ULong => Some(4),
+            Type::LongLong | Type::ULongLong => Some(5),
+            _ => None,
+        }
+    }
+
+    // integer promotion: everything narrower than int fits in an int
+    pub fn promote(self) -> Type {
+        match self.rank() {
+            Some(rank) if rank < 3 => Type::Int,
+            _ => self,
+        }
+    }
+
+    // default argument promotion, for arguments not matching any declared parameter
+    pub fn promote_argument(self) -> Type {
+        match self {
+            Type::Float => Type::Double,
+            t => t.promote(),
+        }
+    }
+
+    pub fn va_list() -> Type {
+        Type::Array(Box::new(Type::Struct(VA_LIST_TAG.to_string())), Length::Constant(1))
+    }
+
+    pub fn to_unsigned(&self) -> Type {
+        match self {
+            Type::Char | Type::SChar => Type::UChar,
+            Type::Short => Type::UShort,
+            Type::Int => Type::UInt,
+            Type::Long => Type::ULong,
+            Type::LongLong => Type::ULongLong,
+            t => t.clone(),
+        }
+    }
+
+    pub fn is_floating(&self) -> bool {
+        matches!(self.unqualified(), Type::Float | Type::Double)
+    }
+
+    pub fn is_arithmetic(&self) -> bool {
+        self.is_integer() || self.is_floating()
+    }
+
+    pub fn is_pointer(&self) -> bool {
+        matches!(self.unqualified(), Type::Pointer(_))
+    }
+
+    pub fn is_void_pointer(&self) -> bool {
+        self.pointee().map(Type::unqualified) == Some(&Type::Void)
+    }
+
+    pub fn is_scalar(&self) -> bool {
+        self.is_arithmetic() || self.is_pointer()
+    }
+
+    pub fn is_array(&self) -> bool {
+        matches!(self.unqualified(), Type::Array(_, _))
+    }
+
+    pub fn is_function(&self) -> bool {
+        matches!(self.unqualified(), Type::Function(_, _, _))
+    }
+
+    pub fn pointee(&self) -> Option<&Type> {
+        match self.unqualified() {
+            Type::Pointer(t) => Some(t),
+            _ => None,
+        }
+    }
+
+    // arrays used as values are converted to a pointer to their first element
+    // and functions to a pointer to themselves
+    pub fn decay(self) -> Type {
+        match self {
+            Type::Array(t, _) => Type::Pointer(t),
+            t @ Type::Function(_, _, _) => Type::Pointer(Box::new(t)),
+            t => t,
+        }
+    }
 }
 
 pub type Identifier = String;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageClass {
+    Static,
+    Extern,
+}
+
 #[derive(Debug)]
 pub struct Program {
     pub funs: Vec<Function>,
+    pub globals: Vec<Global>,
+    pub structs: Vec<StructDef>,
 }
 
 impl Program {
-    pub fn new(funs: Vec<Function>) -> Self {
-        Self { funs }
+    pub fn new(items: Vec<TopLevel>) -> Self {
+        let mut funs = Vec::new();
+        let mut globals = Vec::new();
+        let mut structs = Vec::new();
+        for item in items {
+            match item {
+                TopLevel::Function(f) => funs.push(f),
+                TopLevel::Global(g) => globals.push(g),
+                TopLevel::Struct(Some(s)) => structs.push(s),
+                TopLevel::Struct(None) => {}
+            }
+        }
+        Self {
+            funs,
+            globals,
+            structs,
+        }
     }
 }
 
+#[derive(Debug)]
+pub enum TopLevel {
+    Function(Function),
+    Global(Global),
+    Struct(Option<StructDef>), // None for forward declarations
+}
+
+#[derive(Debug)]
+pub struct StructDef {
+    pub name: Identifier,
+    pub fields: Vec<(Type, Identifier)>,
+    pub span: Span,
+}
+
+impl StructDef {
+    pub fn new(name: Identifier, fields: Vec<(Type, Identifier)>, span: Span) -> Self {
+        Self { name, fields, span }
+    }
+}
+
+#[derive(Debug)]
+pub struct Global {
+    pub name: Identifier,
+    pub typename: Type,
+    pub value: Option<Expression>,
+    pub storage: Option<StorageClass>,
+    pub span: Span,
+}
+
+impl Global {
+    pub fn new(
+        name: Identifier,
+        typename: Type,
+        value: Option<Expression>,
+        storage: Option<StorageClass>,
+        span: Span,
+    ) -> Self {
+        Self {
+            name,
+            typename,
+            value,
+            storage,
+            span,
+        }
+    }
+
+    // extern declarations without initializer only refer to an object defined elsewhere
+    pub fn is_definition(&self) -> bool {
+        self.value.is_some() || self.storage != Some(StorageClass::Extern)
+    }
+}
 
 #[derive(Debug)]
 pub struct Function {
     pub name: String,
+    pub return_type: Type,
     pub args: Vec<(Type, Identifier)>,
+    pub variadic: bool,
+    pub storage: Option<StorageClass>,
     pub statements: Option<Vec<Statement>>,
+    pub span: Span,
 }
 
 impl Function {
     pub fn new(
         name: String,
+        return_type: Type,
         args: Vec<(Type, Identifier)>,
+        variadic: bool,
+        storage: Option<StorageClass>,
         statements: Option<Vec<Statement>>,
+        span: Span,
     ) -> Self {
         Self {
             name,
+            return_type,
             statements,
             args,
+            variadic,
+            storage,
+            span,
         }
     }
 }
 
 #[derive(Debug)]
-pub enum Statement {
-    Declaration(Type, Identifier, Option<Expression>),
-    Return(Expression), // TODO: empty return
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+}
+
+impl Statement {
+    pub fn new(kind: StatementKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+#[derive(Debug)]
+pub enum StatementKind {
+    Declaration(Type, Identifier, Option<Expression>, Option<StorageClass>),
+    Return(Option<Expression>),
     Expression(Option<Expression>),
     If(Expression, Box<Statement>, Option<Box<Statement>>),
     Compound(Vec<Statement>),
@@ -52,32 +340,71 @@ pub enum Statement {
         Box<Statement>,
     ),
     ForDecl(
-        Box<Statement>,
+        Vec<Statement>,
         Expression,
         Option<Expression>,
         Box<Statement>,
     ),
     While(Expression, Box<Statement>),
     Do(Box<Statement>, Expression),
+    Switch(Expression, Box<Statement>),
+    Case(Expression, Box<Statement>),
+    Default(Box<Statement>),
+    Labeled(Identifier, Box<Statement>),
+    Goto(Identifier),
     Break,
     Continue,
 }
 
-#[derive(Debug)]
-pub enum Expression {
+#[derive(Debug, Clone)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+impl Expression {
+    pub fn new(kind: ExpressionKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+
+    // a binary operation, spanning from its first operand to its second
+    pub fn binary(
+        operation: fn(Box<Expression>, Box<Expression>) -> ExpressionKind,
+        e1: Expression,
+        e2: Expression,
+    ) -> Self {
+        let span = e1.span.to(&e2.span);
+        Self::new(operation(Box::new(e1), Box::new(e2)), span)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ExpressionKind {
     Identifier(Identifier),
-    Literal(usize),
+    Literal(usize, Type),
+    FloatLiteral(f64, Type),
+    StringLiteral(Vec<u8>),
     Minus(Box<Expression>),
     BinaryNot(Box<Expression>),
     LogicalNot(Box<Expression>),
-    PreIncrement(Identifier),
-    PreDecrement(Identifier),
-    PostIncrement(Identifier),
-    PostDecrement(Identifier),
+    AddressOf(Box<Expression>),
+    Deref(Box<Expression>),
+    Cast(Type, Box<Expression>),
+    Member(Box<Expression>, Identifier),
+    PreIncrement(Box<Expression>),
+    PreDecrement(Box<Expression>),
+    PostIncrement(Box<Expression>),
+    PostDecrement(Box<Expression>),
     Subtract(Box<Expression>, Box<Expression>),
     Add(Box<Expression>, Box<Expression>),
     Divide(Box<Expression>, Box<Expression>),
     Multiply(Box<Expression>, Box<Expression>),
+    Modulo(Box<Expression>, Box<Expression>),
+    BitwiseAnd(Box<Expression>, Box<Expression>),
+    BitwiseOr(Box<Expression>, Box<Expression>),
+    BitwiseXor(Box<Expression>, Box<Expression>),
+    ShiftLeft(Box<Expression>, Box<Expression>),
+    ShiftRight(Box<Expression>, Box<Expression>),
     And(Box<Expression>, Box<Expression>),
     Or(Box<Expression>, Box<Expression>),
     Equal(Box<Expression>, Box<Expression>),
@@ -86,7 +413,47 @@ pub enum Expression {
     LessThanOrEqual(Box<Expression>, Box<Expression>),
     GreaterThan(Box<Expression>, Box<Expression>),
     GreaterThanOrEqual(Box<Expression>, Box<Expression>),
-    Assignment(Identifier, Box<Expression>),
+    Assignment(Box<Expression>, Box<Expression>),
+    CompoundAssignment(CompoundOperator, Box<Expression>, Box<Expression>),
+    Comma(Box<Expression>, Box<Expression>),
     Conditional(Box<Expression>, Box<Expression>, Box<Expression>),
-    FunCall(Identifier, Vec<Expression>),
+    FunCall(Box<Expression>, Vec<Expression>),
+    VaStart(Box<Expression>),
+    VaArg(Box<Expression>, Type),
+    SizeOf(Type),
+    SizeOfExpr(Box<Expression>),
+    AlignOf(Type),
+}
+
+// the operators combined with an assignment, as in `a += b`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompoundOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    BitwiseAnd,
+    BitwiseOr,
+    BitwiseXor,
+    ShiftLeft,
+    ShiftRight,
+}
+
+impl CompoundOperator {
+    // the binary operation a compound assignment stores the result of
+    pub fn operation(self) -> fn(Box<Expression>, Box<Expression>) -> ExpressionKind {
+        match self {
+            CompoundOperator::Add => ExpressionKind::Add,
+            CompoundOperator::Subtract => ExpressionKind::Subtract,
+            CompoundOperator::Multiply => ExpressionKind::Multiply,
+            CompoundOperator::Divide => ExpressionKind::Divide,
+            CompoundOperator::Modulo => ExpressionKind::Modulo,
+            CompoundOperator::BitwiseAnd => ExpressionKind::BitwiseAnd,
+            CompoundOperator::BitwiseOr => ExpressionKind::BitwiseOr,
+            CompoundOperator::BitwiseXor => ExpressionKind::BitwiseXor,
+            CompoundOperator::ShiftLeft => ExpressionKind::ShiftLeft,
+            CompoundOperator::ShiftRight => ExpressionKind::ShiftRight,
+        }
+    }
 }
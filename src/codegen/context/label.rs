@@ -1,11 +1,17 @@
+use indexmap::IndexMap;
+
 #[derive(Debug)]
 pub struct LabelGenerator {
     next_label: usize,
+    strings: IndexMap<Vec<u8>, String>,
 }
 
 impl LabelGenerator {
     pub fn new() -> Self {
-        Self { next_label: 0 }
+        Self {
+            next_label: 0,
+            strings: IndexMap::new(),
+        }
     }
 
     pub fn unique_label(&mut self) -> String {
@@ -13,4 +19,32 @@ impl LabelGenerator {
         self.next_label += 1;
         format!("_{}", res)
     }
+
+    // user labels live in their function's namespace, apart from the generated _N labels
+    pub fn user_label(function: &str, label: &str) -> String {
+        format!(".L{}.{}", function, label)
+    }
+
+    // local statics live in their function's namespace too, the _N suffix sets apart
+    // those of different blocks
+    pub fn static_label(&mut self, function: &str, id: &str) -> String {
+        let unique = self.unique_label();
+        format!(".L{}.{}.{}", function, id, unique)
+    }
+
+    // identical string literals share the same label
+    pub fn string_label(&mut self, s: &[u8]) -> String {
+        let next = self.strings.len();
+        self.strings
+            .entry(s.to_vec())
+            .or_insert_with(|| format!(".Lstr{}", next))
+            .clone()
+    }
+
+    pub fn strings(&self) -> Vec<(Vec<u8>, String)> {
+        self.strings
+            .iter()
+            .map(|(s, label)| (s.clone(), label.clone()))
+            .collect()
+    }
 }
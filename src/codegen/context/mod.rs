@@ -5,56 +5,221 @@ use std::sync::{Arc, Mutex};
 use self::label::LabelGenerator;
 use self::variable::VariableMap;
 use crate::ast::*;
+use crate::layout::Layouts;
+use crate::typing::{arithmetic_type, Signature, TypeEnv};
+use indexmap::IndexMap;
+use std::collections::HashSet;
 
 #[derive(Debug)]
 pub struct Context {
     labels: Arc<Mutex<LabelGenerator>>,
     vars: Arc<Mutex<VariableMap>>,
-    outer_loop: Option<(String, String)>,
+    functions: Arc<Mutex<IndexMap<Identifier, Signature>>>,
+    internal: Arc<Mutex<HashSet<Identifier>>>, // globals and functions with internal linkage
+    layouts: Arc<Layouts>,
+    frame_size: Arc<Mutex<usize>>,
+    function: Option<Identifier>,
+    return_type: Option<Type>,
+    loop_cont: Option<String>,
+    break_label: Option<String>,
+    cases: Option<Arc<IndexMap<Option<i64>, String>>>,
 }
 
 impl Context {
-    pub fn empty() -> Self {
+    pub fn new(layouts: Layouts) -> Self {
         Self {
             labels: Arc::new(Mutex::new(LabelGenerator::new())),
             vars: Arc::new(Mutex::new(VariableMap::empty())),
-            outer_loop: None,
+            functions: Arc::new(Mutex::new(IndexMap::new())),
+            internal: Arc::new(Mutex::new(HashSet::new())),
+            layouts: Arc::new(layouts),
+            frame_size: Arc::new(Mutex::new(0)),
+            function: None,
+            return_type: None,
+            loop_cont: None,
+            break_label: None,
+            cases: None,
         }
     }
 
     pub fn function_scope(&mut self, fun: &Function) -> Self {
+        let vars = VariableMap::with_args(&self.vars.lock().unwrap(), &fun.args);
         Self {
-            labels: Arc::clone(&mut self.labels),
-            vars: Arc::new(Mutex::new(VariableMap::with_args(&fun.args))),
-            outer_loop: None,
+            labels: Arc::clone(&self.labels),
+            frame_size: Arc::new(Mutex::new(vars.stack_size())),
+            vars: Arc::new(Mutex::new(vars)),
+            functions: Arc::clone(&self.functions),
+            internal: Arc::clone(&self.internal),
+            layouts: Arc::clone(&self.layouts),
+            function: Some(fun.name.clone()),
+            return_type: Some(fun.return_type.clone()),
+            loop_cont: None,
+            break_label: None,
+            cases: None,
         }
     }
 
     pub fn inner_scope(&mut self) -> Self {
         Self {
-            labels: Arc::clone(&mut self.labels),
+            labels: Arc::clone(&self.labels),
             vars: Arc::new(Mutex::new(VariableMap::extend(&self.vars.lock().unwrap()))),
-            outer_loop: self.outer_loop.clone(),
+            functions: Arc::clone(&self.functions),
+            internal: Arc::clone(&self.internal),
+            layouts: Arc::clone(&self.layouts),
+            frame_size: Arc::clone(&self.frame_size),
+            function: self.function.clone(),
+            return_type: self.return_type.clone(),
+            loop_cont: self.loop_cont.clone(),
+            break_label: self.break_label.clone(),
+            cases: self.cases.clone(),
         }
     }
     pub fn inner_loop(&mut self, outer_loop_cont: String, outer_loop_end: String) -> Self {
         Self {
             labels: self.labels.clone(),
             vars: self.vars.clone(),
-            outer_loop: Some((outer_loop_cont, outer_loop_end)),
+            functions: self.functions.clone(),
+            internal: self.internal.clone(),
+            layouts: self.layouts.clone(),
+            frame_size: self.frame_size.clone(),
+            function: self.function.clone(),
+            return_type: self.return_type.clone(),
+            loop_cont: Some(outer_loop_cont),
+            break_label: Some(outer_loop_end),
+            cases: self.cases.clone(),
+        }
+    }
+    // break leaves the switch but continue still targets the enclosing loop
+    pub fn inner_switch(
+        &mut self,
+        switch_end: String,
+        cases: IndexMap<Option<i64>, String>,
+    ) -> Self {
+        Self {
+            labels: self.labels.clone(),
+            vars: self.vars.clone(),
+            functions: self.functions.clone(),
+            internal: self.internal.clone(),
+            layouts: self.layouts.clone(),
+            frame_size: self.frame_size.clone(),
+            function: self.function.clone(),
+            return_type: self.return_type.clone(),
+            loop_cont: self.loop_cont.clone(),
+            break_label: Some(switch_end),
+            cases: Some(Arc::new(cases)),
         }
     }
 
     pub fn unique_label(&mut self) -> String {
         self.labels.lock().unwrap().unique_label()
     }
+    pub fn user_label(&self, label: &Identifier) -> String {
+        let function = self.function.as_ref().expect("No function context");
+        LabelGenerator::user_label(function, label)
+    }
+    pub fn static_label(&mut self, id: &Identifier) -> String {
+        let function = self.function.as_ref().expect("No function context");
+        self.labels.lock().unwrap().static_label(function, id)
+    }
+    pub fn string_label(&mut self, s: &[u8]) -> String {
+        self.labels.lock().unwrap().string_label(s)
+    }
+    pub fn strings(&self) -> Vec<(Vec<u8>, String)> {
+        self.labels.lock().unwrap().strings()
+    }
     pub fn resolve(&self, id: &Identifier) -> String {
+        // functions are addressed through their symbol unless shadowed by a variable
+        if self.variable_type(id).is_none() && self.function_type(id).is_some() {
+            return format!("[rip+{}]", id);
+        }
         self.vars.lock().unwrap().resolve(id)
     }
     pub fn declare(&mut self, id: Identifier, t: Type) {
-        self.vars.lock().unwrap().declare(id, t)
+        let (size, align) = (self.size_of(&t), self.align_of(&t));
+        let mut vars = self.vars.lock().unwrap();
+        vars.declare(id, t, size, align);
+        let mut frame_size = self.frame_size.lock().unwrap();
+        *frame_size = (*frame_size).max(vars.stack_size());
+    }
+    pub fn declare_symbol(&mut self, id: Identifier, t: Type, symbol: String) {
+        self.vars.lock().unwrap().declare_symbol(id, t, symbol)
+    }
+    pub fn declare_global(&mut self, global: &Global) {
+        if global.storage == Some(StorageClass::Static) {
+            self.internal.lock().unwrap().insert(global.name.clone());
+        }
+        self.vars
+            .lock()
+            .unwrap()
+            .declare_global(global.name.clone(), global.typename.clone())
+    }
+    pub fn declare_function(&mut self, fun: &Function) {
+        if fun.storage == Some(StorageClass::Static) {
+            self.internal.lock().unwrap().insert(fun.name.clone());
+        }
+        self.functions
+            .lock()
+            .unwrap()
+            .insert(fun.name.clone(), fun.signature());
+    }
+    pub fn is_internal(&self, id: &Identifier) -> bool {
+        self.internal.lock().unwrap().contains(id)
+    }
+    pub fn size_of(&self, t: &Type) -> usize {
+        self.layouts.size(t)
+    }
+    pub fn align_of(&self, t: &Type) -> usize {
+        self.layouts.align(t)
+    }
+    pub fn member_offset(&self, e: &Expression, id: &Identifier) -> usize {
+        match self.object_type_of(e).unqualified() {
+            Type::Struct(name) => self.layouts.get(name).unwrap().fields[id].1,
+            _ => unreachable!("member access on a non-struct"),
+        }
+    }
+    pub fn frame_size(&self) -> usize {
+        *self.frame_size.lock().unwrap()
+    }
+    pub fn type_of(&self, e: &Expression) -> Type {
+        e.typ(self).expect("expression should have been type checked")
+    }
+    // type both operands of a comparison are converted to
+    pub fn comparison_type(&self, e1: &Expression, e2: &Expression) -> Type {
+        match (self.type_of(e1), self.type_of(e2)) {
+            (t, _) | (_, t) if t.is_pointer() => t,
+            (t1, t2) => arithmetic_type(&t1, &t2, &self.layouts),
+        }
+    }
+    pub fn object_type_of(&self, e: &Expression) -> Type {
+        e.object_type(self)
+            .expect("expression should have been type checked")
+    }
+    pub fn return_type(&self) -> &Type {
+        self.return_type.as_ref().expect("No function context")
+    }
+    pub fn loop_cont(&self) -> Option<&String> {
+        self.loop_cont.as_ref()
+    }
+    pub fn break_label(&self) -> Option<&String> {
+        self.break_label.as_ref()
+    }
+    // label of the case with the given value, None for default
+    pub fn case_label(&self, value: Option<i64>) -> &String {
+        &self.cases.as_ref().expect("No switch context")[&value]
+    }
+}
+
+impl TypeEnv for Context {
+    fn variable_type(&self, id: &Identifier) -> Option<Type> {
+        self.vars.lock().unwrap().variable_type(id)
+    }
+    fn function_type(&self, id: &Identifier) -> Option<Signature> {
+        self.functions.lock().unwrap().get(id).cloned()
+    }
+    fn enclosing_function(&self) -> Option<&Identifier> {
+        self.function.as_ref()
     }
-    pub fn outer_loop(&self) -> Option<&(String, String)> {
-        self.outer_loop.as_ref()
+    fn layouts(&self) -> &Layouts {
+        &self.layouts
     }
 }
@@ -1,31 +1,55 @@
-use super::{Identifier, Type, super::CALLER_REGS};
+use super::{Identifier, Type};
+use crate::codegen::{arg_locations, ArgLocation};
 use indexmap::IndexMap;
 
+// where a variable lives: at some offset from rbp, or behind a symbol
+#[derive(Debug, Clone)]
+enum Location {
+    Frame(isize),
+    Symbol(String),
+}
+
 #[derive(Debug)]
 pub struct VariableMap {
-    args: IndexMap<Identifier, Type>,
-    extern_ids: IndexMap<Identifier, Type>,
-    ids: IndexMap<Identifier, Type>,
+    globals: IndexMap<Identifier, Type>,
+    args: IndexMap<Identifier, (Type, Location)>,
+    extern_ids: IndexMap<Identifier, (Type, Location)>,
+    ids: IndexMap<Identifier, (Type, Location)>,
+    stack_size: usize,
 }
 
 impl VariableMap {
     pub fn empty() -> Self {
         Self {
+            globals: IndexMap::new(),
             args: IndexMap::new(),
             extern_ids: IndexMap::new(),
             ids: IndexMap::new(),
+            stack_size: 0,
         }
     }
 
-    pub fn with_args(args: &Vec<(Type, Identifier)>) -> Self {
+    pub fn with_args(orig: &VariableMap, args: &[(Type, Identifier)]) -> Self {
         let mut args_map = IndexMap::new();
-        for (typename, id) in args {
-            args_map.insert(id.clone(), typename.clone());
+        let mut stack_size = 0;
+        let locations = arg_locations(args.iter().map(|(t, _)| t));
+        for ((typename, id), location) in args.iter().zip(locations) {
+            let offset = match location {
+                ArgLocation::Stack(index) => 8 * (2 + index as isize), // 64 bit offsetting, starting at [rbp+16]
+                _ => {
+                    // register arguments get spilled to the start of the frame
+                    stack_size += 8;
+                    -(stack_size as isize)
+                }
+            };
+            args_map.insert(id.clone(), (typename.clone(), Location::Frame(offset)));
         }
         Self {
+            globals: orig.globals.clone(),
             args: args_map,
             extern_ids: IndexMap::new(),
             ids: IndexMap::new(),
+            stack_size,
         }
     }
 
@@ -36,50 +60,62 @@ impl VariableMap {
         }
 
         Self {
+            globals: orig.globals.clone(),
             args: orig.args.clone(),
             extern_ids,
             ids: IndexMap::new(),
+            stack_size: orig.stack_size,
         }
     }
 
-    pub fn declare(&mut self, id: Identifier, t: Type) {
+    pub fn declare(&mut self, id: Identifier, t: Type, size: usize, align: usize) {
         if self.ids.contains_key(&id) {
             panic!("duplicate variable definition");
         }
-        self.ids.insert(id, t);
+        self.stack_size = (self.stack_size + size).next_multiple_of(align);
+        self.ids.insert(id, (t, Location::Frame(-(self.stack_size as isize))));
     }
 
-    pub fn resolve(&self, id: &Identifier) -> String {
-        if self.ids.contains_key(id) {
-            self.resolve_intern(id)
-        } else if self.extern_ids.contains_key(id) {
-            self.resolve_extern(id)
-        } else if self.args.contains_key(id) {
-            self.resolve_arg(id)
-        } else {
-            panic!(format!("undefined variable: {}; {:?}", id, self));
+    // local statics and extern declarations take no room in the frame
+    pub fn declare_symbol(&mut self, id: Identifier, t: Type, symbol: String) {
+        if self.ids.contains_key(&id) {
+            panic!("duplicate variable definition");
         }
+        self.ids.insert(id, (t, Location::Symbol(symbol)));
     }
 
-    fn resolve_intern(&self, id: &Identifier) -> String {
-        let (i, _, _) = self.ids.get_full(id).unwrap();
-        let index = self.extern_ids.len() + i;
+    pub fn declare_global(&mut self, id: Identifier, t: Type) {
+        self.globals.insert(id, t);
+    }
 
-        let offset = -8 * (1 + index as isize); // 64 bit offsetting, starting at [rbp-8]
-        format!("QWORD PTR [rbp{}]", offset)
+    pub fn stack_size(&self) -> usize {
+        self.stack_size
     }
-    fn resolve_extern(&self, id: &Identifier) -> String {
-        let (index, _, _) = self.extern_ids.get_full(id).unwrap();
-        let offset = -8 * (1 + index as isize); // 64 bit offsetting, starting at [rbp-8]
-        format!("QWORD PTR [rbp{}]", offset)
+
+    pub fn variable_type(&self, id: &Identifier) -> Option<Type> {
+        self.lookup(id)
+            .map(|(t, _)| t.clone())
+            .or_else(|| self.globals.get(id).cloned())
     }
-    fn resolve_arg(&self, id: &Identifier) -> String {
-        let (index, _, _) = self.args.get_full(id).unwrap();
-        if index < 6 {
-            CALLER_REGS[index].to_string()
+
+    pub fn resolve(&self, id: &Identifier) -> String {
+        if let Some((_, location)) = self.lookup(id) {
+            match location {
+                Location::Frame(offset) if *offset < 0 => format!("[rbp{}]", offset),
+                Location::Frame(offset) => format!("[rbp+{}]", offset),
+                Location::Symbol(symbol) => format!("[rip+{}]", symbol),
+            }
+        } else if self.globals.contains_key(id) {
+            format!("[rip+{}]", id)
         } else {
-            let offset = 8 * (2 + (index as isize - 6)); // 64 bit offsetting, starting at [rbp+16]
-            format!("QWORD PTR [rbp+{}]", offset)
+            panic!("undefined variable: {}; {:?}", id, self);
         }
     }
+
+    fn lookup(&self, id: &Identifier) -> Option<&(Type, Location)> {
+        self.ids
+            .get(id)
+            .or_else(|| self.extern_ids.get(id))
+            .or_else(|| self.args.get(id))
+    }
 }
@@ -1,121 +1,301 @@
 use super::Context;
 use super::Generator;
-use super::CALLER_REGS;
+use super::{arg_locations, ArgLocation, CALLER_REGS, REG_SAVE_AREA, REG_SAVE_AREA_SIZE};
+use super::{cast, convert, copy, load, sse_operation, sse_result, sse_suffix, store};
 use crate::ast::*;
+use crate::typing::TypeEnv;
 use std::io::{self, Write};
 
 impl Generator for Expression {
     fn generate(&self, stream: &mut impl Write, ctx: &mut Context) -> io::Result<()> {
-        match self {
-            Expression::FunCall(id, args) => {
-                let mut to_restore = Vec::new();
-                for (i, arg) in args.iter().take(6).enumerate() {
-                    arg.generate(stream, ctx)?;
-                    writeln!(
-                        stream,
-                        "push {}\n\
-                         mov {}, rax",
-                        CALLER_REGS[i], CALLER_REGS[i]
-                    )?;
-                    to_restore.push(CALLER_REGS[i]);
+        match &self.kind {
+            ExpressionKind::FunCall(callee, args) => {
+                let (return_type, mut arg_types, variadic) = match ctx.type_of(callee).pointee() {
+                    Some(Type::Function(return_type, arg_types, variadic)) => {
+                        ((**return_type).clone(), arg_types.clone(), *variadic)
+                    }
+                    _ => unreachable!("called object should have been type checked"),
+                };
+                // arguments matching no declared parameter get the default promotions
+                for arg in args.iter().skip(arg_types.len()) {
+                    arg_types.push(ctx.type_of(arg).promote_argument());
+                }
+                let locations = arg_locations(arg_types.iter());
+                let on_stack = |location: &ArgLocation| matches!(location, ArgLocation::Stack(_));
+                // keep rsp 16-byte aligned at the call whatever was pushed before
+                let in_sse = |location: &ArgLocation| matches!(location, ArgLocation::Sse(_));
+                let stacked = locations.iter().filter(|l| on_stack(l)).count();
+                let sse_regs = locations.iter().filter(|l| in_sse(l)).count();
+                writeln!(
+                    stream,
+                    "mov rax, rsp\n\
+                     and rsp, -16\n\
+                     push rax\n\
+                     push rax"
+                )?;
+                if stacked % 2 == 1 {
+                    writeln!(stream, "sub rsp, 8")?;
                 }
 
-                let mut stacked = 0;
-                for arg in args.iter().skip(6).rev() {
-                    arg.generate(stream, ctx)?;
+                // stack arguments are pushed first so that register ones end up on top
+                let args: Vec<_> = args.iter().zip(arg_types.iter()).zip(locations).collect();
+                let (stack_args, reg_args): (Vec<_>, Vec<_>) =
+                    args.into_iter().partition(|(_, l)| on_stack(l));
+                for ((arg, t), _) in reg_args.iter().chain(stack_args.iter()).rev() {
+                    arg.generate_as(t, stream, ctx)?;
                     writeln!(stream, "push rax")?;
-                    stacked += 1;
                 }
+                // named functions are called directly, anything else through its value
+                let target = match &callee.kind {
+                    ExpressionKind::Identifier(id) if ctx.variable_type(id).is_none() => id.clone(),
+                    _ => {
+                        callee.generate(stream, ctx)?;
+                        writeln!(stream, "mov r11, rax")?;
+                        "r11".to_string()
+                    }
+                };
+                for (_, location) in reg_args.iter() {
+                    match location {
+                        ArgLocation::Integer(i) => writeln!(stream, "pop {}", CALLER_REGS[*i])?,
+                        ArgLocation::Sse(i) => writeln!(stream, "pop rax\nmovq xmm{}, rax", i)?,
+                        ArgLocation::Stack(_) => unreachable!(),
+                    }
+                }
+                if variadic {
+                    // variadic callees read the number of vector registers used from al
+                    writeln!(stream, "mov eax, {}", sse_regs)?;
+                }
+
                 writeln!(
                     stream,
                     "call {}\n\
-                     add rsp, {}",
-                    id,
-                    stacked * 8 // 64 bit offsetting
+                     add rsp, {}\n\
+                     pop rsp",
+                    target,
+                    (stacked + stacked % 2) * 8 // 64 bit offsetting
                 )?;
-
-                // restore register arguments
-                for reg in to_restore.iter().rev() {
-                    writeln!(stream, "pop {}", reg)?;
+                if return_type.is_floating() {
+                    sse_result(stream, &return_type)?;
+                } else {
+                    // only the low bits of a narrow return value are meaningful
+                    cast(stream, &return_type)?;
                 }
             }
-            Expression::Conditional(cond, exp, alt) => {
+            // fills the fields of the va_list: gp_offset and fp_offset locate the next argument in
+            // the register save area, past the named ones, and overflow_arg_area on the stack
+            ExpressionKind::VaStart(ap) => {
+                let function = ctx.enclosing_function().expect("No function context").clone();
+                let (_, arg_types, _) = ctx.function_type(&function).expect("Undeclared function");
+                let locations = arg_locations(arg_types.iter());
+                let count = |f: fn(&ArgLocation) -> bool| locations.iter().filter(|l| f(l)).count();
+                let integer = count(|l| matches!(l, ArgLocation::Integer(_)));
+                let sse = count(|l| matches!(l, ArgLocation::Sse(_)));
+                let stacked = count(|l| matches!(l, ArgLocation::Stack(_)));
+
+                ap.generate(stream, ctx)?;
+                writeln!(
+                    stream,
+                    "mov DWORD PTR [rax], {}\n\
+                     mov DWORD PTR [rax+4], {}\n\
+                     lea rcx, [rbp+{}]\n\
+                     mov QWORD PTR [rax+8], rcx\n\
+                     lea rcx, {}\n\
+                     mov QWORD PTR [rax+16], rcx",
+                    8 * integer,
+                    8 * CALLER_REGS.len() + 16 * sse,
+                    16 + 8 * stacked,
+                    ctx.resolve(&REG_SAVE_AREA.to_string())
+                )?;
+            }
+            // takes the next argument from the register save area while it has some left,
+            // then from the stack
+            ExpressionKind::VaArg(ap, t) => {
+                let (offset_field, limit, step) = if t.is_floating() {
+                    (4, REG_SAVE_AREA_SIZE, 16)
+                } else {
+                    (0, 8 * CALLER_REGS.len(), 8)
+                };
+                let stack = ctx.unique_label();
+                let end = ctx.unique_label();
+
+                ap.generate(stream, ctx)?;
+                writeln!(
+                    stream,
+                    "mov rcx, rax\n\
+                     mov eax, DWORD PTR [rcx+{offset_field}]\n\
+                     cmp eax, {limit}\n\
+                     jae {stack}\n\
+                     mov rdx, rax\n\
+                     add rdx, QWORD PTR [rcx+16]\n\
+                     add eax, {step}\n\
+                     mov DWORD PTR [rcx+{offset_field}], eax\n\
+                     jmp {end}\n\
+                     {stack}:\n\
+                     mov rdx, QWORD PTR [rcx+8]\n\
+                     lea rax, [rdx+8]\n\
+                     mov QWORD PTR [rcx+8], rax\n\
+                     {end}:\n\
+                     mov rax, rdx",
+                    offset_field = offset_field,
+                    limit = limit,
+                    step = step,
+                    stack = stack,
+                    end = end
+                )?;
+                load(stream, t)?;
+            }
+            ExpressionKind::Conditional(cond, exp, alt) => {
+                let t = ctx.type_of(self);
                 let alt_label = ctx.unique_label();
                 let post_conditional = ctx.unique_label();
 
-                cond.generate(stream, ctx)?;
+                cond.generate_condition(stream, ctx)?;
                 writeln!(
                     stream,
                     "cmp rax, 0\n\
                      je {}",
                     alt_label
                 )?;
-                exp.generate(stream, ctx)?;
+                exp.generate_as(&t, stream, ctx)?;
                 writeln!(
                     stream,
                     "jmp {}\n\
                      {}:",
                     post_conditional, alt_label
                 )?;
-                alt.generate(stream, ctx)?;
+                alt.generate_as(&t, stream, ctx)?;
                 writeln!(stream, "{}:", post_conditional)?;
             }
-            Expression::PreIncrement(id) => {
-                let var = ctx.resolve(&id);
-                writeln!(
-                    stream,
-                    "add {}, 1\n\
-                     mov rax, {}",
-                    var, var
-                )?;
+            ExpressionKind::PreIncrement(e)
+            | ExpressionKind::PreDecrement(e)
+            | ExpressionKind::PostIncrement(e)
+            | ExpressionKind::PostDecrement(e) => {
+                let t = ctx.type_of(e);
+                let step = t.pointee().map_or(1, |t| ctx.size_of(t));
+                let op = match &self.kind {
+                    ExpressionKind::PreIncrement(_) | ExpressionKind::PostIncrement(_) => "add",
+                    _ => "sub",
+                };
+                e.generate_address(stream, ctx)?;
+                writeln!(stream, "mov rcx, rax")?;
+                load(stream, &t)?;
+                let post = matches!(
+                    self.kind,
+                    ExpressionKind::PostIncrement(_) | ExpressionKind::PostDecrement(_)
+                );
+                if post {
+                    writeln!(stream, "push rax")?;
+                }
+                if t.is_floating() {
+                    writeln!(
+                        stream,
+                        "movq xmm0, rax\n\
+                         mov edx, 1\n\
+                         cvtsi2{s} xmm1, rdx\n\
+                         {op}{s} xmm0, xmm1",
+                        s = sse_suffix(&t),
+                        op = op
+                    )?;
+                    sse_result(stream, &t)?;
+                } else {
+                    writeln!(stream, "{} rax, {}", op, step)?;
+                    cast(stream, &t)?;
+                }
+                store(stream, &t)?;
+                if post {
+                    writeln!(stream, "pop rax")?;
+                }
             }
-            Expression::PreDecrement(id) => {
-                let var = ctx.resolve(&id);
-                writeln!(
-                    stream,
-                    "sub {}, 1\n\
-                     mov rax, {}",
-                    var, var
-                )?;
+            ExpressionKind::Identifier(_) => {
+                self.generate_address(stream, ctx)?;
+                load(stream, &ctx.object_type_of(self))?;
             }
-            Expression::PostIncrement(id) => {
-                let var = ctx.resolve(&id);
-                writeln!(
-                    stream,
-                    "mov rax, {}\n\
-                     add {}, 1",
-                    var, var
-                )?;
+            ExpressionKind::Deref(e) => {
+                e.generate(stream, ctx)?;
+                load(stream, &ctx.object_type_of(self))?;
             }
-            Expression::PostDecrement(id) => {
-                let var = ctx.resolve(&id);
-                writeln!(
-                    stream,
-                    "mov rax, {}\n\
-                     sub {}, 1",
-                    var, var
-                )?;
+            ExpressionKind::Member(_, _) => {
+                self.generate_address(stream, ctx)?;
+                load(stream, &ctx.object_type_of(self))?;
             }
-            Expression::Identifier(id) => {
-                writeln!(stream, "mov rax, {}", ctx.resolve(&id))?;
+            ExpressionKind::Cast(t, e) => {
+                e.generate_as(t, stream, ctx)?;
             }
-            Expression::Assignment(id, e) => {
-                e.generate(stream, ctx)?;
-                writeln!(stream, "mov {}, rax", ctx.resolve(&id))?;
+            ExpressionKind::AddressOf(e) => {
+                e.generate_address(stream, ctx)?;
+            }
+            ExpressionKind::Assignment(lhs, e) => {
+                lhs.generate_address(stream, ctx)?;
+                writeln!(stream, "push rax")?;
+                let t = ctx.type_of(lhs);
+                e.generate_as(&t, stream, ctx)?;
+                writeln!(stream, "pop rcx")?;
+                match t {
+                    Type::Struct(_) => copy(stream, ctx.size_of(&t))?,
+                    _ => store(stream, &t)?,
+                }
+            }
+            // lhs op= rhs is generated as *address = *address op rhs, with the address of lhs
+            // computed once and kept in a hidden variable, lhs having to be evaluated only once
+            ExpressionKind::CompoundAssignment(op, lhs, rhs) => {
+                let address = format!(".address{}", ctx.unique_label());
+                let t = Type::Pointer(Box::new(ctx.object_type_of(lhs)));
+                ctx.declare(address.clone(), t);
+                lhs.generate_address(stream, ctx)?;
+                writeln!(stream, "mov QWORD PTR {}, rax", ctx.resolve(&address))?;
+
+                let span = &lhs.span;
+                let target = || {
+                    let address = ExpressionKind::Identifier(address.clone());
+                    let address = Box::new(Expression::new(address, span.clone()));
+                    Box::new(Expression::new(ExpressionKind::Deref(address), span.clone()))
+                };
+                let value = op.operation()(target(), rhs.clone());
+                let value = Expression::new(value, self.span.clone());
+                let assignment = ExpressionKind::Assignment(target(), Box::new(value));
+                Expression::new(assignment, self.span.clone()).generate(stream, ctx)?;
+            }
+            ExpressionKind::StringLiteral(_) => {
+                self.generate_address(stream, ctx)?;
+            }
+            ExpressionKind::Comma(e1, e2) => {
+                e1.generate(stream, ctx)?;
+                e2.generate(stream, ctx)?;
             }
-            Expression::Literal(i) => {
+            ExpressionKind::Literal(i, _) => {
                 writeln!(stream, "mov rax, {}", i)?;
             }
-            Expression::Minus(e) => {
+            ExpressionKind::FloatLiteral(f, t) => {
+                let bits = match t {
+                    Type::Float => u64::from((*f as f32).to_bits()),
+                    _ => f.to_bits(),
+                };
+                writeln!(stream, "mov rax, {}", bits)?;
+            }
+            ExpressionKind::SizeOf(_)
+            | ExpressionKind::SizeOfExpr(_)
+            | ExpressionKind::AlignOf(_) => {
+                unreachable!("compile time operators are lowered before code generation")
+            }
+            ExpressionKind::Minus(e) => {
                 e.generate(stream, ctx)?;
-                writeln!(stream, "neg rax")?;
+                match ctx.type_of(self) {
+                    // flip the sign bit
+                    Type::Float => writeln!(stream, "btc eax, 31")?,
+                    Type::Double => writeln!(stream, "btc rax, 63")?,
+                    t => {
+                        writeln!(stream, "neg rax")?;
+                        cast(stream, &t)?;
+                    }
+                }
             }
-            Expression::BinaryNot(e) => {
+            ExpressionKind::BinaryNot(e) => {
                 e.generate(stream, ctx)?;
                 writeln!(stream, "not rax")?;
+                cast(stream, &ctx.type_of(self))?;
             }
-            Expression::LogicalNot(e) => {
-                e.generate(stream, ctx)?;
+            ExpressionKind::LogicalNot(e) => {
+                e.generate_condition(stream, ctx)?;
                 writeln!(
                     stream,
                     "cmp rax, 0\n\
@@ -123,52 +303,133 @@ impl Generator for Expression {
                      sete al"
                 )?;
             }
-            Expression::Subtract(e1, e2) => {
-                e2.generate(stream, ctx)?;
+            ExpressionKind::Subtract(e1, e2) => {
+                let (t1, t2) = (ctx.type_of(e1), ctx.type_of(e2));
+                let t = ctx.type_of(self);
+                e2.generate_as(&t, stream, ctx)?;
+                if let (Some(t), false) = (t1.pointee(), t2.is_pointer()) {
+                    writeln!(stream, "imul rax, {}", ctx.size_of(t))?;
+                }
                 writeln!(stream, "push rax")?;
-                e1.generate(stream, ctx)?;
-                writeln!(
-                    stream,
-                    "pop rcx\n\
-                     sub rax, rcx"
-                )?;
+                e1.generate_as(&t, stream, ctx)?;
+                writeln!(stream, "pop rcx")?;
+                if t.is_floating() {
+                    sse_operation(stream, "sub", &t)?;
+                } else {
+                    writeln!(stream, "sub rax, rcx")?;
+                    cast(stream, &t)?;
+                }
+                if let (Some(t), true) = (t1.pointee(), t2.is_pointer()) {
+                    // difference of pointers counts elements, not bytes
+                    writeln!(
+                        stream,
+                        "cqo\n\
+                         mov rcx, {}\n\
+                         idiv rcx",
+                        ctx.size_of(t)
+                    )?;
+                }
             }
-            Expression::Add(e1, e2) => {
-                e1.generate(stream, ctx)?;
+            ExpressionKind::Add(e1, e2) => {
+                let (t1, t2) = (ctx.type_of(e1), ctx.type_of(e2));
+                let t = ctx.type_of(self);
+                e1.generate_as(&t, stream, ctx)?;
+                if let Some(t) = t2.pointee() {
+                    writeln!(stream, "imul rax, {}", ctx.size_of(t))?;
+                }
                 writeln!(stream, "push rax")?;
-                e2.generate(stream, ctx)?;
+                e2.generate_as(&t, stream, ctx)?;
+                if let Some(t) = t1.pointee() {
+                    writeln!(stream, "imul rax, {}", ctx.size_of(t))?;
+                }
+                writeln!(stream, "pop rcx")?;
+                if t.is_floating() {
+                    sse_operation(stream, "add", &t)?;
+                } else {
+                    writeln!(stream, "add rax, rcx")?;
+                    cast(stream, &t)?;
+                }
+            }
+            ExpressionKind::Multiply(e1, e2) => {
+                let t = ctx.type_of(self);
+                e1.generate_as(&t, stream, ctx)?;
+                writeln!(stream, "push rax")?;
+                e2.generate_as(&t, stream, ctx)?;
+                writeln!(stream, "pop rcx")?;
+                if t.is_floating() {
+                    sse_operation(stream, "mul", &t)?;
+                } else {
+                    writeln!(stream, "imul rax, rcx")?;
+                    cast(stream, &t)?;
+                }
+            }
+            ExpressionKind::Divide(e1, e2) | ExpressionKind::Modulo(e1, e2) => {
+                let t = ctx.type_of(self);
+                e1.generate_as(&t, stream, ctx)?;
+                writeln!(stream, "push rax")?;
+                e2.generate_as(&t, stream, ctx)?;
                 writeln!(
                     stream,
-                    "pop rcx\n\
-                     add rax, rcx"
+                    "mov rcx, rax\n\
+                     pop rax"
                 )?;
+                if t.is_floating() {
+                    sse_operation(stream, "div", &t)?;
+                } else {
+                    if t.is_unsigned() {
+                        writeln!(stream, "mov rdx, 0\ndiv rcx")?;
+                    } else {
+                        writeln!(stream, "cqo\nidiv rcx")?;
+                    }
+                    if let ExpressionKind::Modulo(_, _) = self.kind {
+                        // the remainder is left in rdx
+                        writeln!(stream, "mov rax, rdx")?;
+                    }
+                    cast(stream, &t)?;
+                }
             }
-            Expression::Multiply(e1, e2) => {
-                e1.generate(stream, ctx)?;
+            ExpressionKind::BitwiseAnd(e1, e2)
+            | ExpressionKind::BitwiseOr(e1, e2)
+            | ExpressionKind::BitwiseXor(e1, e2) => {
+                let t = ctx.type_of(self);
+                let op = match &self.kind {
+                    ExpressionKind::BitwiseAnd(_, _) => "and",
+                    ExpressionKind::BitwiseOr(_, _) => "or",
+                    _ => "xor",
+                };
+                e1.generate_as(&t, stream, ctx)?;
                 writeln!(stream, "push rax")?;
-                e2.generate(stream, ctx)?;
+                e2.generate_as(&t, stream, ctx)?;
                 writeln!(
                     stream,
                     "pop rcx\n\
-                     imul rax, rcx"
+                     {} rax, rcx",
+                    op
                 )?;
             }
-            Expression::Divide(e1, e2) => {
-                e1.generate(stream, ctx)?;
+            ExpressionKind::ShiftLeft(e1, e2) | ExpressionKind::ShiftRight(e1, e2) => {
+                let t = ctx.type_of(self);
+                let op = match &self.kind {
+                    ExpressionKind::ShiftLeft(_, _) => "sal",
+                    _ if t.is_unsigned() => "shr",
+                    _ => "sar",
+                };
+                e1.generate_as(&t, stream, ctx)?;
                 writeln!(stream, "push rax")?;
                 e2.generate(stream, ctx)?;
                 writeln!(
                     stream,
-                    "mov rdx, 0\n\
-                     mov rcx, rax\n\
+                    "mov rcx, rax\n\
                      pop rax\n\
-                     idiv rcx"
+                     {} rax, cl",
+                    op
                 )?;
+                cast(stream, &t)?;
             }
-            Expression::And(e1, e2) => {
+            ExpressionKind::And(e1, e2) => {
                 let end = ctx.unique_label();
                 let second_clause = ctx.unique_label();
-                e1.generate(stream, ctx)?;
+                e1.generate_condition(stream, ctx)?;
                 writeln!(
                     stream,
                     "cmp rax, 0\n\
@@ -177,7 +438,7 @@ impl Generator for Expression {
                      {}:",
                     second_clause, end, second_clause
                 )?;
-                e2.generate(stream, ctx)?;
+                e2.generate_condition(stream, ctx)?;
                 writeln!(
                     stream,
                     "cmp rax, 0\n\
@@ -187,10 +448,10 @@ impl Generator for Expression {
                     end
                 )?;
             }
-            Expression::Or(e1, e2) => {
+            ExpressionKind::Or(e1, e2) => {
                 let end = ctx.unique_label();
                 let second_clause = ctx.unique_label();
-                e1.generate(stream, ctx)?;
+                e1.generate_condition(stream, ctx)?;
                 writeln!(
                     stream,
                     "cmp rax, 0\n\
@@ -200,7 +461,7 @@ impl Generator for Expression {
                      {}:",
                     second_clause, end, second_clause
                 )?;
-                e2.generate(stream, ctx)?;
+                e2.generate_condition(stream, ctx)?;
                 writeln!(
                     stream,
                     "cmp rax, 0\n\
@@ -210,79 +471,130 @@ impl Generator for Expression {
                     end
                 )?;
             }
-            Expression::Equal(e1, e2) => {
-                e1.generate(stream, ctx)?;
-                writeln!(stream, "push rax")?;
-                e2.generate(stream, ctx)?;
-                writeln!(
-                    stream,
-                    "pop rcx\n\
-                     cmp rcx, rax\n\
-                     mov rax, 0\n\
-                     sete al"
-                )?;
-            }
-            Expression::NotEqual(e1, e2) => {
-                e1.generate(stream, ctx)?;
-                writeln!(stream, "push rax")?;
-                e2.generate(stream, ctx)?;
-                writeln!(
-                    stream,
-                    "pop rcx\n\
-                     cmp rcx, rax\n\
-                     mov rax, 0\n\
-                     setne al"
-                )?;
-            }
-            Expression::LessThan(e1, e2) => {
-                e1.generate(stream, ctx)?;
-                writeln!(stream, "push rax")?;
-                e2.generate(stream, ctx)?;
-                writeln!(
-                    stream,
-                    "pop rcx\n\
-                     cmp rcx, rax\n\
-                     mov rax, 0\n\
-                     setl al"
-                )?;
-            }
-            Expression::LessThanOrEqual(e1, e2) => {
-                e1.generate(stream, ctx)?;
+            ExpressionKind::Equal(e1, e2)
+            | ExpressionKind::NotEqual(e1, e2)
+            | ExpressionKind::LessThan(e1, e2)
+            | ExpressionKind::LessThanOrEqual(e1, e2)
+            | ExpressionKind::GreaterThan(e1, e2)
+            | ExpressionKind::GreaterThanOrEqual(e1, e2) => {
+                let t = ctx.comparison_type(e1, e2);
+                e1.generate_as(&t, stream, ctx)?;
                 writeln!(stream, "push rax")?;
-                e2.generate(stream, ctx)?;
-                writeln!(
-                    stream,
-                    "pop rcx\n\
-                     cmp rcx, rax\n\
-                     mov rax, 0\n\
-                     setle al"
-                )?;
+                e2.generate_as(&t, stream, ctx)?;
+                writeln!(stream, "pop rcx")?;
+                if t.is_floating() {
+                    // unordered comparisons (NaN operands) set PF and compare unequal
+                    let (operands, set) = match &self.kind {
+                        ExpressionKind::Equal(_, _) => {
+                            ("xmm0, xmm1", "sete al\nsetnp cl\nand al, cl")
+                        }
+                        ExpressionKind::NotEqual(_, _) => {
+                            ("xmm0, xmm1", "setne al\nsetp cl\nor al, cl")
+                        }
+                        ExpressionKind::LessThan(_, _) => ("xmm1, xmm0", "seta al"),
+                        ExpressionKind::LessThanOrEqual(_, _) => ("xmm1, xmm0", "setae al"),
+                        ExpressionKind::GreaterThan(_, _) => ("xmm0, xmm1", "seta al"),
+                        _ => ("xmm0, xmm1", "setae al"),
+                    };
+                    writeln!(
+                        stream,
+                        "movq xmm0, rcx\n\
+                         movq xmm1, rax\n\
+                         ucomi{} {}\n\
+                         mov rax, 0\n\
+                         {}",
+                        sse_suffix(&t),
+                        operands,
+                        set
+                    )?;
+                } else {
+                    let unsigned = t.is_unsigned() || t.is_pointer();
+                    let set = match &self.kind {
+                        ExpressionKind::Equal(_, _) => "sete",
+                        ExpressionKind::NotEqual(_, _) => "setne",
+                        ExpressionKind::LessThan(_, _) if unsigned => "setb",
+                        ExpressionKind::LessThan(_, _) => "setl",
+                        ExpressionKind::LessThanOrEqual(_, _) if unsigned => "setbe",
+                        ExpressionKind::LessThanOrEqual(_, _) => "setle",
+                        ExpressionKind::GreaterThan(_, _) if unsigned => "seta",
+                        ExpressionKind::GreaterThan(_, _) => "setg",
+                        ExpressionKind::GreaterThanOrEqual(_, _) if unsigned => "setae",
+                        _ => "setge",
+                    };
+                    writeln!(
+                        stream,
+                        "cmp rcx, rax\n\
+                         mov rax, 0\n\
+                         {} al",
+                        set
+                    )?;
+                }
             }
-            Expression::GreaterThan(e1, e2) => {
-                e1.generate(stream, ctx)?;
-                writeln!(stream, "push rax")?;
-                e2.generate(stream, ctx)?;
-                writeln!(
-                    stream,
-                    "pop rcx\n\
-                     cmp rcx, rax\n\
-                     mov rax, 0\n\
-                     setg al"
-                )?;
+        }
+        Ok(())
+    }
+}
+
+impl Expression {
+    // evaluates the expression and converts its value to type t
+    pub(super) fn generate_as(
+        &self,
+        t: &Type,
+        stream: &mut impl Write,
+        ctx: &mut Context,
+    ) -> io::Result<()> {
+        self.generate(stream, ctx)?;
+        let from = ctx.type_of(self);
+        convert(stream, ctx, &from, t)
+    }
+
+    // evaluates a controlling expression so that rax is zero exactly when it is false
+    pub(super) fn generate_condition(
+        &self,
+        stream: &mut impl Write,
+        ctx: &mut Context,
+    ) -> io::Result<()> {
+        self.generate(stream, ctx)?;
+        let t = ctx.type_of(self);
+        if t.is_floating() {
+            // -0.0 is false even though its bits are not all zero
+            writeln!(
+                stream,
+                "movq xmm0, rax\n\
+                 xorps xmm1, xmm1\n\
+                 ucomi{} xmm0, xmm1\n\
+                 setne al\n\
+                 setp cl\n\
+                 or al, cl\n\
+                 movzx eax, al",
+                sse_suffix(&t)
+            )?;
+        }
+        Ok(())
+    }
+
+    // leaves the address of an lvalue in rax
+    pub(super) fn generate_address(
+        &self,
+        stream: &mut impl Write,
+        ctx: &mut Context,
+    ) -> io::Result<()> {
+        match &self.kind {
+            ExpressionKind::Identifier(id) => writeln!(stream, "lea rax, {}", ctx.resolve(id)),
+            ExpressionKind::Deref(e) => e.generate(stream, ctx),
+            // struct values, like those of assignments or conditionals, are their address
+            ExpressionKind::Member(e, id) => {
+                if e.is_lvalue() {
+                    e.generate_address(stream, ctx)?;
+                } else {
+                    e.generate(stream, ctx)?;
+                }
+                writeln!(stream, "add rax, {}", ctx.member_offset(e, id))
             }
-            Expression::GreaterThanOrEqual(e1, e2) => {
-                e1.generate(stream, ctx)?;
-                writeln!(stream, "push rax")?;
-                e2.generate(stream, ctx)?;
-                writeln!(
-                    stream,
-                    "pop rcx\n\
-                     cmp rcx, rax\n\
-                     mov rax, 0\n\
-                     setge al"
-                )?;
+            ExpressionKind::StringLiteral(s) => {
+                writeln!(stream, "lea rax, [rip+{}]", ctx.string_label(s))
             }
+            _ => unreachable!("not an lvalue"),
         }
-        Ok(())
     }
 }
@@ -1,5 +1,7 @@
 use super::Context;
 use super::Generator;
+use super::{arg_locations, ArgLocation, CALLER_REGS, SSE_ARG_REGS};
+use super::{REG_SAVE_AREA, REG_SAVE_AREA_SIZE};
 use crate::ast::*;
 use std::io::{self, Write};
 
@@ -15,24 +17,55 @@ impl Generator for Function {
                      _main:"
                 )?;
             } else {
-                writeln!(
-                    stream,
-                    ".globl {}\n\
-                     {}:",
-                    self.name, self.name
-                )?;
+                if !ctx.is_internal(&self.name) {
+                    writeln!(stream, ".globl {}", self.name)?;
+                }
+                writeln!(stream, "{}:", self.name)?;
+            }
+
+            // the frame size is only known once the body has been generated
+            let mut fun_ctx = ctx.function_scope(self);
+            if self.variadic {
+                let length = Length::Constant(REG_SAVE_AREA_SIZE / 8);
+                let area = Type::Array(Box::new(Type::Long), length);
+                fun_ctx.declare(REG_SAVE_AREA.to_string(), area);
+            }
+            let mut body = Vec::new();
+            for s in statements.iter() {
+                s.generate(&mut body, &mut fun_ctx)?;
             }
 
             writeln!(
                 stream,
                 "push rbp\n\
-                 mov rbp, rsp"
+                 mov rbp, rsp\n\
+                 sub rsp, {}",
+                fun_ctx.frame_size().next_multiple_of(16)
             )?;
-
-            let mut fun_ctx = ctx.function_scope(&self);
-            for s in statements.iter() {
-                s.generate(stream, &mut fun_ctx)?;
+            let locations = arg_locations(self.args.iter().map(|(t, _)| t));
+            for (location, (_, id)) in locations.into_iter().zip(self.args.iter()) {
+                match location {
+                    ArgLocation::Integer(i) => {
+                        let slot = fun_ctx.resolve(id);
+                        writeln!(stream, "mov QWORD PTR {}, {}", slot, CALLER_REGS[i])?
+                    }
+                    ArgLocation::Sse(i) => {
+                        writeln!(stream, "movsd QWORD PTR {}, xmm{}", fun_ctx.resolve(id), i)?
+                    }
+                    ArgLocation::Stack(_) => {}
+                }
+            }
+            if self.variadic {
+                writeln!(stream, "lea rax, {}", fun_ctx.resolve(&REG_SAVE_AREA.to_string()))?;
+                for (i, reg) in CALLER_REGS.iter().enumerate() {
+                    writeln!(stream, "mov QWORD PTR [rax+{}], {}", 8 * i, reg)?;
+                }
+                for i in 0..SSE_ARG_REGS {
+                    let offset = 8 * CALLER_REGS.len() + 16 * i;
+                    writeln!(stream, "movsd QWORD PTR [rax+{}], xmm{}", offset, i)?;
+                }
             }
+            stream.write_all(&body)?;
 
             writeln!(
                 stream,
@@ -3,12 +3,16 @@ mod expression;
 mod function;
 mod statement;
 
-use crate::ast::Program;
+use crate::ast::*;
+use crate::constant::{evaluate_address, evaluate_as};
+use crate::layout::Layouts;
 use self::context::Context;
+use indexmap::IndexMap;
 use std::io::{self, Write};
 
 pub fn codegen(program: &Program, stream: &mut impl Write) -> io::Result<()> {
-    program.generate(stream, &mut Context::empty())
+    let layouts = Layouts::new(&program.structs).expect("struct layouts should have been validated");
+    program.generate(stream, &mut Context::new(layouts))
 }
 
 trait Generator {
@@ -18,11 +22,438 @@ trait Generator {
 impl Generator for Program {
     fn generate(&self, stream: &mut impl Write, ctx: &mut Context) -> io::Result<()> {
         writeln!(stream, ".intel_syntax noprefix")?;
+
+        // tentative definitions all refer to the same object, keep the initialized one
+        // extern declarations only make the object visible
+        // functions are declared first, as initializers may take their address
+        for function in self.funs.iter() {
+            ctx.declare_function(function);
+        }
+        let mut globals: IndexMap<&Identifier, &Global> = IndexMap::new();
+        for global in self.globals.iter() {
+            ctx.declare_global(global);
+            if global.is_definition()
+                && (global.value.is_some() || !globals.contains_key(&global.name))
+            {
+                globals.insert(&global.name, global);
+            }
+        }
+        for global in globals.values() {
+            global.generate(stream, ctx)?;
+        }
+
+        writeln!(stream, ".text")?;
         for function in self.funs.iter() {
             function.generate(stream, ctx)?;
         }
+
+        writeln!(stream, ".section .rodata")?;
+        for (s, label) in ctx.strings() {
+            writeln!(stream, "{}:\n.string \"{}\"", label, escape(&s))?;
+        }
         Ok(())
     }
 }
 
+impl Generator for Global {
+    fn generate(&self, stream: &mut impl Write, ctx: &mut Context) -> io::Result<()> {
+        if !ctx.is_internal(&self.name) {
+            writeln!(stream, ".globl {}", self.name)?;
+        }
+        static_storage(stream, ctx, &self.name, &self.typename, self.value.as_ref())
+    }
+}
+
+// emits the storage of an object living for the whole program under the given label
+fn static_storage(
+    stream: &mut impl Write,
+    ctx: &mut Context,
+    label: &str,
+    t: &Type,
+    value: Option<&Expression>,
+) -> io::Result<()> {
+    let value = match value {
+        Some(Expression {
+            kind: ExpressionKind::StringLiteral(s),
+            ..
+        }) => Some(ctx.string_label(s)),
+        Some(e) => evaluate_as(e, t).map(|i| i.to_string()).or_else(|| {
+            // the symbols of objects with static storage are those they are addressed with
+            let symbol = |id: &Identifier| {
+                let address = ctx.resolve(id);
+                address.strip_prefix("[rip+")?.strip_suffix(']').map(str::to_string)
+            };
+            evaluate_address(e, ctx, &symbol).map(|(symbol, offset)| match offset {
+                0 => symbol,
+                _ => format!("{}{:+}", symbol, offset),
+            })
+        }),
+        None => None,
+    };
+    // initialized constants can be write protected
+    let section = if t.qualifiers().constant { ".section .rodata" } else { ".data" };
+    match value {
+        Some(value) => writeln!(
+            stream,
+            "{}\n\
+             .align {}\n\
+             {}:\n\
+             {} {}",
+            section,
+            ctx.align_of(t),
+            label,
+            data_directive(t),
+            value
+        ),
+        None => writeln!(
+            stream,
+            ".bss\n\
+             .align {}\n\
+             {}:\n\
+             .zero {}",
+            ctx.align_of(t),
+            label,
+            ctx.size_of(t)
+        ),
+    }
+}
+
 const CALLER_REGS: [&str; 6] = [ "rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+const SSE_ARG_REGS: usize = 8; // xmm0 to xmm7
+
+// where variadic functions spill all argument registers for va_arg to find them:
+// the integer ones first, then the xmm ones 16 bytes apart
+const REG_SAVE_AREA: &str = ".reg_save_area";
+const REG_SAVE_AREA_SIZE: usize = 8 * 6 + 16 * SSE_ARG_REGS;
+
+#[derive(Debug, Clone, Copy)]
+enum ArgLocation {
+    Integer(usize), // index in CALLER_REGS
+    Sse(usize),     // index of the xmm register
+    Stack(usize),   // index among the arguments passed on the stack
+}
+
+// where the System V calling convention puts arguments of the given types
+fn arg_locations<'a>(types: impl Iterator<Item = &'a Type>) -> Vec<ArgLocation> {
+    let (mut integer, mut sse, mut stack) = (0, 0, 0);
+    types
+        .map(|t| {
+            let (counter, location): (&mut usize, fn(usize) -> ArgLocation) =
+                if t.is_floating() && sse < SSE_ARG_REGS {
+                    (&mut sse, ArgLocation::Sse)
+                } else if !t.is_floating() && integer < CALLER_REGS.len() {
+                    (&mut integer, ArgLocation::Integer)
+                } else {
+                    (&mut stack, ArgLocation::Stack)
+                };
+            *counter += 1;
+            location(*counter - 1)
+        })
+        .collect()
+}
+
+fn data_directive(t: &Type) -> &'static str {
+    match t.unqualified() {
+        Type::Char | Type::SChar | Type::UChar => ".byte",
+        Type::Short | Type::UShort => ".value",
+        Type::Int | Type::UInt | Type::Float => ".long",
+        Type::Long
+        | Type::ULong
+        | Type::LongLong
+        | Type::ULongLong
+        | Type::Double
+        | Type::Pointer(_) => ".quad",
+        _ => unreachable!("aggregates have no scalar initializer"),
+    }
+}
+
+fn escape(s: &[u8]) -> String {
+    s.iter()
+        .map(|&c| match c {
+            b'"' => "\\\"".to_string(),
+            b'\\' => "\\\\".to_string(),
+            0x20..=0x7e => (c as char).to_string(),
+            _ => format!("\\{:03o}", c),
+        })
+        .collect()
+}
+
+// loads the value of type t stored at the address in rax into rax
+// integers always occupy the whole of rax, sign or zero extended according to their type
+// floating point values are kept in rax as their raw bits, floats zero extended
+fn load(stream: &mut impl Write, t: &Type) -> io::Result<()> {
+    match t.unqualified() {
+        Type::Char | Type::SChar => writeln!(stream, "movsx rax, BYTE PTR [rax]"),
+        Type::UChar => writeln!(stream, "movzx eax, BYTE PTR [rax]"),
+        Type::Short => writeln!(stream, "movsx rax, WORD PTR [rax]"),
+        Type::UShort => writeln!(stream, "movzx eax, WORD PTR [rax]"),
+        Type::Int => writeln!(stream, "movsxd rax, DWORD PTR [rax]"),
+        Type::UInt | Type::Float => writeln!(stream, "mov eax, DWORD PTR [rax]"),
+        Type::Long
+        | Type::ULong
+        | Type::LongLong
+        | Type::ULongLong
+        | Type::Double
+        | Type::Pointer(_) => {
+            writeln!(stream, "mov rax, QWORD PTR [rax]")
+        }
+        // aggregates evaluate to their address
+        Type::Array(_, _) | Type::Struct(_) | Type::Function(_, _, _) => Ok(()),
+        Type::Void | Type::Qualified(_, _) => Ok(()),
+    }
+}
+
+// stores the value of type t in rax at the address in rcx
+fn store(stream: &mut impl Write, t: &Type) -> io::Result<()> {
+    match t.unqualified() {
+        Type::Char | Type::SChar | Type::UChar => writeln!(stream, "mov BYTE PTR [rcx], al"),
+        Type::Short | Type::UShort => writeln!(stream, "mov WORD PTR [rcx], ax"),
+        Type::Int | Type::UInt | Type::Float => writeln!(stream, "mov DWORD PTR [rcx], eax"),
+        Type::Long
+        | Type::ULong
+        | Type::LongLong
+        | Type::ULongLong
+        | Type::Double
+        | Type::Pointer(_) => {
+            writeln!(stream, "mov QWORD PTR [rcx], rax")
+        }
+        _ => unreachable!("aggregates are copied, not stored"),
+    }
+}
+
+// copies size bytes from the address in rax to the address in rcx, leaving the latter in rax
+fn copy(stream: &mut impl Write, size: usize) -> io::Result<()> {
+    writeln!(
+        stream,
+        "push rcx\n\
+         mov rsi, rax\n\
+         mov rdi, rcx\n\
+         mov rcx, {}\n\
+         rep movsb\n\
+         pop rax",
+        size
+    )
+}
+
+// converts the scalar in rax to type t
+// as scalars always fill rax according to their own type, only the target type matters:
+// narrowing truncates then extends back, widening and pointer conversions keep the bits
+fn cast(stream: &mut impl Write, t: &Type) -> io::Result<()> {
+    match t.unqualified() {
+        Type::Char | Type::SChar => writeln!(stream, "movsx rax, al"),
+        Type::UChar => writeln!(stream, "movzx eax, al"),
+        Type::Short => writeln!(stream, "movsx rax, ax"),
+        Type::UShort => writeln!(stream, "movzx eax, ax"),
+        Type::Int => writeln!(stream, "movsxd rax, eax"),
+        Type::UInt => writeln!(stream, "mov eax, eax"),
+        _ => Ok(()),
+    }
+}
+
+// converts the value of type from in rax to type to
+fn convert(stream: &mut impl Write, ctx: &mut Context, from: &Type, to: &Type) -> io::Result<()> {
+    let (from, to) = (from.unqualified(), to.unqualified());
+    match (from.is_floating(), to.is_floating()) {
+        (false, false) => cast(stream, to),
+        (false, true) => {
+            let s = sse_suffix(to);
+            if matches!(from, Type::ULong | Type::ULongLong) {
+                // cvtsi2sd only takes signed operands: halve values with the top bit set,
+                // keeping the lowest bit for rounding, then double the result
+                let big = ctx.unique_label();
+                let end = ctx.unique_label();
+                writeln!(
+                    stream,
+                    "test rax, rax\n\
+                     js {big}\n\
+                     cvtsi2{s} xmm0, rax\n\
+                     jmp {end}\n\
+                     {big}:\n\
+                     mov rcx, rax\n\
+                     shr rcx, 1\n\
+                     and eax, 1\n\
+                     or rcx, rax\n\
+                     cvtsi2{s} xmm0, rcx\n\
+                     add{s} xmm0, xmm0\n\
+                     {end}:",
+                    big = big,
+                    end = end,
+                    s = s
+                )?;
+            } else {
+                writeln!(stream, "cvtsi2{} xmm0, rax", s)?;
+            }
+            sse_result(stream, to)
+        }
+        (true, false) => {
+            let s = sse_suffix(from);
+            if matches!(to, Type::ULong | Type::ULongLong) {
+                // cvttsd2si only produces signed results: values from 2^63 up are offset
+                let big = ctx.unique_label();
+                let end = ctx.unique_label();
+                let two_pow_63: u64 = match from {
+                    Type::Float => 0x5f00_0000,
+                    _ => 0x43e0_0000_0000_0000,
+                };
+                writeln!(
+                    stream,
+                    "movq xmm0, rax\n\
+                     mov rcx, {two_pow_63}\n\
+                     movq xmm1, rcx\n\
+                     ucomi{s} xmm0, xmm1\n\
+                     jae {big}\n\
+                     cvtt{s}2si rax, xmm0\n\
+                     jmp {end}\n\
+                     {big}:\n\
+                     sub{s} xmm0, xmm1\n\
+                     cvtt{s}2si rax, xmm0\n\
+                     btc rax, 63\n\
+                     {end}:",
+                    two_pow_63 = two_pow_63,
+                    big = big,
+                    end = end,
+                    s = s
+                )
+            } else {
+                writeln!(stream, "movq xmm0, rax\ncvtt{}2si rax, xmm0", s)?;
+                cast(stream, to)
+            }
+        }
+        (true, true) if from == to => Ok(()),
+        (true, true) => {
+            writeln!(
+                stream,
+                "movq xmm0, rax\n\
+                 cvt{}2{} xmm0, xmm0",
+                sse_suffix(from),
+                sse_suffix(to)
+            )?;
+            sse_result(stream, to)
+        }
+    }
+}
+
+// suffix of the SSE instructions operating on floating type t
+fn sse_suffix(t: &Type) -> &'static str {
+    match t.unqualified() {
+        Type::Float => "ss",
+        _ => "sd",
+    }
+}
+
+// moves the floating value of type t in xmm0 to rax
+fn sse_result(stream: &mut impl Write, t: &Type) -> io::Result<()> {
+    match t.unqualified() {
+        Type::Float => writeln!(stream, "movd eax, xmm0"),
+        _ => writeln!(stream, "movq rax, xmm0"),
+    }
+}
+
+// applies the SSE instruction op to the floating operands of type t in rax and rcx
+fn sse_operation(stream: &mut impl Write, op: &str, t: &Type) -> io::Result<()> {
+    writeln!(
+        stream,
+        "movq xmm0, rax\n\
+         movq xmm1, rcx\n\
+         {}{} xmm0, xmm1",
+        op,
+        sse_suffix(t)
+    )?;
+    sse_result(stream, t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexing::lex;
+    use crate::lowering::lower;
+    use crate::parsing::parse;
+    use crate::validation::validate;
+
+    // the assembly generated for a valid program
+    fn compile(source: &str) -> String {
+        let mut program = parse(&lex(source).unwrap()).unwrap();
+        lower(&mut program).unwrap();
+        validate(&program).unwrap();
+        let mut output = Vec::new();
+        codegen(&program, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    const POINTS: &str = "struct P { int x; long y; };
+        struct P a;
+        struct P b;";
+
+    #[test]
+    fn member_of_conditional() {
+        let asm = compile(&format!("{} long f(int c) {{ return (c ? a : b).y; }}", POINTS));
+        assert!(asm.contains("add rax, 8"));
+    }
+
+    #[test]
+    fn member_of_assignment() {
+        let asm = compile(&format!("{} long f() {{ return (a = b).y; }}", POINTS));
+        assert!(asm.contains("add rax, 8"));
+    }
+
+    const INTEGERS: &str = "char c;
+        unsigned char uc;
+        short s;
+        unsigned short us;
+        unsigned u;
+        long l;";
+
+    #[test]
+    fn loads_extend_from_their_width() {
+        let asm = compile(&format!("{} long f() {{ return c + uc + s + us + u; }}", INTEGERS));
+        assert!(asm.contains("movsx rax, BYTE PTR [rax]"));
+        assert!(asm.contains("movzx eax, BYTE PTR [rax]"));
+        assert!(asm.contains("movsx rax, WORD PTR [rax]"));
+        assert!(asm.contains("movzx eax, WORD PTR [rax]"));
+        assert!(asm.contains("mov eax, DWORD PTR [rax]"));
+    }
+
+    #[test]
+    fn stores_truncate_to_their_width() {
+        let asm = compile(&format!("{} void f() {{ c = l; s = l; u = l; }}", INTEGERS));
+        assert!(asm.contains("mov BYTE PTR [rcx], al"));
+        assert!(asm.contains("mov WORD PTR [rcx], ax"));
+        assert!(asm.contains("mov DWORD PTR [rcx], eax"));
+    }
+
+    #[test]
+    fn globals_take_their_size() {
+        let asm = compile(INTEGERS);
+        assert!(asm.contains(".align 1\nc:\n.zero 1\n"));
+        assert!(asm.contains(".align 2\ns:\n.zero 2\n"));
+        assert!(asm.contains(".align 4\nu:\n.zero 4\n"));
+        assert!(asm.contains(".align 8\nl:\n.zero 8\n"));
+    }
+
+    #[test]
+    fn indirect_calls() {
+        let asm = compile(
+            "int add(int a, int b) { return a + b; }
+            int (*op)(int, int) = add;
+            int f() { return op(1, 2) + (*op)(3, 4); }",
+        );
+        assert!(asm.contains("op:\n.quad add\n"));
+        assert_eq!(asm.matches("call r11").count(), 2);
+    }
+
+    #[test]
+    fn static_storage() {
+        let asm = compile(
+            "int g[4];
+            int *p = &g[2];
+            static int h = 3;
+            extern int e;
+            int f() { static int n = 5; return n + h + e; }",
+        );
+        assert!(asm.contains(".globl p\n.data\n.align 8\np:\n.quad g+8\n"));
+        // static globals stay local to the file, extern ones are defined elsewhere
+        assert!(!asm.contains(".globl h") && asm.contains("h:\n.long 3\n"));
+        assert!(!asm.contains("e:"));
+        assert!(asm.contains(".Lf.n._0:\n.long 5\n"));
+    }
+}
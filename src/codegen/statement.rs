@@ -1,31 +1,34 @@
 use super::Generator;
 use super::Context;
+use super::{copy, static_storage, store};
 use crate::ast::*;
+use crate::constant::evaluate;
+use indexmap::IndexMap;
 use std::io::{self, Write};
 
 impl Generator for Statement {
     fn generate(&self, stream: &mut impl Write, ctx: &mut Context) -> io::Result<()> {
-        match self {
-            Statement::Continue => {
+        match &self.kind {
+            StatementKind::Continue => {
                 writeln!(
                     stream,
                     "jmp {}",
-                    ctx.outer_loop().expect("No outer loop context").0
+                    ctx.loop_cont().expect("No outer loop context")
                 )?; // FIXME: doesn't always jump over body
             }
-            Statement::Break => {
+            StatementKind::Break => {
                 writeln!(
                     stream,
                     "jmp {}",
-                    ctx.outer_loop().expect("No outer loop context").1
+                    ctx.break_label().expect("No outer loop or switch context")
                 )?;
             }
-            Statement::While(cond, body) => {
+            StatementKind::While(cond, body) => {
                 let beg = ctx.unique_label();
                 let end = ctx.unique_label();
 
                 writeln!(stream, "{}:", beg)?;
-                cond.generate(stream, ctx)?;
+                cond.generate_condition(stream, ctx)?;
                 writeln!(
                     stream,
                     "cmp rax, 0\n\
@@ -40,22 +43,61 @@ impl Generator for Statement {
                     beg, end
                 )?;
             }
-            Statement::Do(body, cond) => {
+            StatementKind::Do(body, cond) => {
                 let beg = ctx.unique_label();
                 let end = ctx.unique_label();
 
                 writeln!(stream, "{}:", beg)?;
                 body.generate(stream, &mut ctx.inner_loop(beg.clone(), end.clone()))?;
-                cond.generate(stream, ctx)?;
+                cond.generate_condition(stream, ctx)?;
                 writeln!(
                     stream,
                     "cmp rax, 0\n\
-                     jne {}`\n\
+                     jne {}\n\
                      {}:",
                     beg, end
                 )?;
             }
-            Statement::For(init, cond, iter, body) => {
+            StatementKind::Switch(cond, body) => {
+                let end = ctx.unique_label();
+                let mut cases = IndexMap::new();
+                for value in body.case_values() {
+                    cases.insert(value, ctx.unique_label());
+                }
+
+                cond.generate(stream, ctx)?;
+                for (value, label) in cases.iter() {
+                    if let Some(value) = value {
+                        writeln!(
+                            stream,
+                            "mov rcx, {}\n\
+                             cmp rax, rcx\n\
+                             je {}",
+                            value, label
+                        )?;
+                    }
+                }
+                writeln!(stream, "jmp {}", cases.get(&None).unwrap_or(&end))?;
+                body.generate(stream, &mut ctx.inner_switch(end.clone(), cases))?;
+                writeln!(stream, "{}:", end)?;
+            }
+            StatementKind::Case(value, stm) => {
+                let value = evaluate(value).expect("case values should have been validated");
+                writeln!(stream, "{}:", ctx.case_label(Some(value)))?;
+                stm.generate(stream, ctx)?;
+            }
+            StatementKind::Default(stm) => {
+                writeln!(stream, "{}:", ctx.case_label(None))?;
+                stm.generate(stream, ctx)?;
+            }
+            StatementKind::Labeled(label, stm) => {
+                writeln!(stream, "{}:", ctx.user_label(label))?;
+                stm.generate(stream, ctx)?;
+            }
+            StatementKind::Goto(label) => {
+                writeln!(stream, "jmp {}", ctx.user_label(label))?;
+            }
+            StatementKind::For(init, cond, iter, body) => {
                 let beg = ctx.unique_label();
                 let cont = ctx.unique_label();
                 let end = ctx.unique_label();
@@ -64,7 +106,7 @@ impl Generator for Statement {
                     init.generate(stream, ctx)?;
                 }
                 writeln!(stream, "{}:", beg)?;
-                cond.generate(stream, ctx)?;
+                cond.generate_condition(stream, ctx)?;
                 writeln!(
                     stream,
                     "cmp rax, 0\n\
@@ -84,14 +126,17 @@ impl Generator for Statement {
                     beg, end
                 )?;
             }
-            Statement::ForDecl(init, cond, iter, body) => {
+            StatementKind::ForDecl(init, cond, iter, body) => {
+                let ctx = &mut ctx.inner_scope();
                 let beg = ctx.unique_label();
                 let cont = ctx.unique_label();
                 let end = ctx.unique_label();
 
-                init.generate(stream, ctx)?;
+                for decl in init.iter() {
+                    decl.generate(stream, ctx)?;
+                }
                 writeln!(stream, "{}:", beg)?;
-                cond.generate(stream, ctx)?;
+                cond.generate_condition(stream, ctx)?;
                 writeln!(
                     stream,
                     "cmp rax, 0\n\
@@ -111,17 +156,18 @@ impl Generator for Statement {
                     beg, end
                 )?;
             }
-            Statement::Compound(stms) => {
+            StatementKind::Compound(stms) => {
+                let mut inner_ctx = ctx.inner_scope();
                 for stm in stms {
-                    stm.generate(stream, &mut ctx.inner_scope())?;
+                    stm.generate(stream, &mut inner_ctx)?;
                 }
             }
-            Statement::If(cond, stm, alt) => match alt {
+            StatementKind::If(cond, stm, alt) => match alt {
                 Some(alt) => {
                     let alt_label = ctx.unique_label();
                     let post_conditional = ctx.unique_label();
 
-                    cond.generate(stream, ctx)?;
+                    cond.generate_condition(stream, ctx)?;
                     writeln!(
                         stream,
                         "cmp rax, 0\n\
@@ -141,7 +187,7 @@ impl Generator for Statement {
                 None => {
                     let post_conditional = ctx.unique_label();
 
-                    cond.generate(stream, ctx)?;
+                    cond.generate_condition(stream, ctx)?;
                     writeln!(
                         stream,
                         "cmp rax, 0\n\
@@ -152,21 +198,39 @@ impl Generator for Statement {
                     writeln!(stream, "{}:", post_conditional)?;
                 }
             },
-            Statement::Declaration(t, id, expr) => {
+            StatementKind::Declaration(t, id, expr, Some(StorageClass::Static)) => {
+                let label = ctx.static_label(id);
+                static_storage(stream, ctx, &label, t, expr.as_ref())?;
+                writeln!(stream, ".text")?;
+                ctx.declare_symbol(id.clone(), t.clone(), label);
+            }
+            StatementKind::Declaration(t, id, _, Some(StorageClass::Extern)) => {
+                ctx.declare_symbol(id.clone(), t.clone(), id.clone());
+            }
+            StatementKind::Declaration(t, id, expr, None) => {
                 ctx.declare(id.clone(), (*t).clone());
                 if let Some(e) = expr {
-                    writeln!(stream, "sub rsp, 8")?;
-                    e.generate(stream, ctx)?;
-                    writeln!(stream, "mov {}, rax", ctx.resolve(&id))?;
+                    e.generate_as(t, stream, ctx)?;
+                    writeln!(stream, "lea rcx, {}", ctx.resolve(id))?;
+                    match t.unqualified() {
+                        Type::Struct(_) => copy(stream, ctx.size_of(t))?,
+                        _ => store(stream, t)?,
+                    }
                 }
             }
-            Statement::Expression(e) => {
+            StatementKind::Expression(e) => {
                 if let Some(e) = e {
                     e.generate(stream, ctx)?;
                 }
             }
-            Statement::Return(e) => {
-                e.generate(stream, ctx)?;
+            StatementKind::Return(e) => {
+                if let Some(e) = e {
+                    let t = ctx.return_type().clone();
+                    e.generate_as(&t, stream, ctx)?;
+                    if t.is_floating() {
+                        writeln!(stream, "movq xmm0, rax")?;
+                    }
+                }
                 writeln!(
                     stream,
                     "mov rsp, rbp\n\
@@ -178,3 +242,36 @@ impl Generator for Statement {
         Ok(())
     }
 }
+
+impl Statement {
+    // values of the cases belonging to this switch body, None standing for default
+    fn case_values(&self) -> Vec<Option<i64>> {
+        match &self.kind {
+            StatementKind::Case(value, stm) => {
+                let mut values = vec![evaluate(value)];
+                values.extend(stm.case_values());
+                values
+            }
+            StatementKind::Default(stm) => {
+                let mut values = vec![None];
+                values.extend(stm.case_values());
+                values
+            }
+            StatementKind::If(_, stm, alt) => {
+                let mut values = stm.case_values();
+                if let Some(alt) = alt {
+                    values.extend(alt.case_values());
+                }
+                values
+            }
+            StatementKind::Compound(stms) => stms.iter().flat_map(Statement::case_values).collect(),
+            StatementKind::Labeled(_, stm)
+            | StatementKind::For(_, _, _, stm)
+            | StatementKind::ForDecl(_, _, _, stm)
+            | StatementKind::While(_, stm)
+            | StatementKind::Do(stm, _) => stm.case_values(),
+            // nested switches own their cases
+            _ => Vec::new(),
+        }
+    }
+}
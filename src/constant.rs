@@ -0,0 +1,314 @@
+use crate::ast::*;
+use crate::layout::Layouts;
+use crate::typing::{arithmetic_type, Signature, TypeEnv};
+
+// constant expressions refer to no variable, only the types of their operands matter
+#[derive(Default)]
+struct Constants(Layouts);
+
+impl TypeEnv for Constants {
+    fn variable_type(&self, _: &Identifier) -> Option<Type> {
+        None
+    }
+
+    fn function_type(&self, _: &Identifier) -> Option<Signature> {
+        None
+    }
+
+    fn enclosing_function(&self) -> Option<&Identifier> {
+        None
+    }
+
+    fn layouts(&self) -> &Layouts {
+        &self.0
+    }
+}
+
+// evaluates an integer constant expression, None if it isn't one
+// values are computed in the type C gives them, then sign or zero extended from it
+pub fn evaluate(expr: &Expression) -> Option<i64> {
+    integer(expr, &Constants::default())
+}
+
+fn integer(expr: &Expression, env: &Constants) -> Option<i64> {
+    let t = expr.typ(env).ok()?;
+    if !t.is_integer() && !t.is_pointer() {
+        return None;
+    }
+    let value = |e: &Expression| integer(e, env);
+    // operands of binary operators are first converted to their common type
+    let common = |e1: &Expression, e2: &Expression| {
+        let (t1, t2) = (e1.typ(env).ok()?, e2.typ(env).ok()?);
+        if !t1.is_integer() || !t2.is_integer() {
+            return None;
+        }
+        let t = arithmetic_type(&t1, &t2, env.layouts());
+        Some((convert(value(e1)?, &t), convert(value(e2)?, &t), t))
+    };
+    // pointers compare as unsigned integers
+    let comparison = |e1: &Expression, e2: &Expression| {
+        if e1.typ(env).ok()?.is_pointer() || e2.typ(env).ok()?.is_pointer() {
+            return Some((value(e1)?, value(e2)?, true));
+        }
+        common(e1, e2).map(|(i1, i2, t)| (i1, i2, t.is_unsigned()))
+    };
+    let compare = |e1: &Expression, e2: &Expression, ordering: fn(std::cmp::Ordering) -> bool| {
+        let (i1, i2, unsigned) = comparison(e1, e2)?;
+        let order = if unsigned { (i1 as u64).cmp(&(i2 as u64)) } else { i1.cmp(&i2) };
+        Some(ordering(order) as i64)
+    };
+
+    let result = match &expr.kind {
+        ExpressionKind::Literal(i, _) => *i as i64,
+        ExpressionKind::Cast(t, e) if t.is_integer() => {
+            value(e).or_else(|| evaluate_floating(e).map(|f| from_floating(f, t)))?
+        }
+        ExpressionKind::Cast(t, e) if t.is_pointer() => value(e)?,
+        ExpressionKind::Minus(e) => value(e)?.wrapping_neg(),
+        ExpressionKind::BinaryNot(e) => !value(e)?,
+        ExpressionKind::LogicalNot(e) => (value(e)? == 0) as i64,
+        ExpressionKind::Add(e1, e2) => {
+            let (i1, i2, _) = common(e1, e2)?;
+            i1.wrapping_add(i2)
+        }
+        ExpressionKind::Subtract(e1, e2) => {
+            let (i1, i2, _) = common(e1, e2)?;
+            i1.wrapping_sub(i2)
+        }
+        ExpressionKind::Multiply(e1, e2) => {
+            let (i1, i2, _) = common(e1, e2)?;
+            i1.wrapping_mul(i2)
+        }
+        ExpressionKind::Divide(e1, e2) => match common(e1, e2)? {
+            (i1, i2, t) if t.is_unsigned() => (i1 as u64).checked_div(i2 as u64)? as i64,
+            (i1, i2, _) => i1.checked_div(i2)?,
+        },
+        ExpressionKind::Modulo(e1, e2) => match common(e1, e2)? {
+            (i1, i2, t) if t.is_unsigned() => (i1 as u64).checked_rem(i2 as u64)? as i64,
+            (i1, i2, _) => i1.checked_rem(i2)?,
+        },
+        ExpressionKind::BitwiseAnd(e1, e2) => {
+            let (i1, i2, _) = common(e1, e2)?;
+            i1 & i2
+        }
+        ExpressionKind::BitwiseOr(e1, e2) => {
+            let (i1, i2, _) = common(e1, e2)?;
+            i1 | i2
+        }
+        ExpressionKind::BitwiseXor(e1, e2) => {
+            let (i1, i2, _) = common(e1, e2)?;
+            i1 ^ i2
+        }
+        // the left operand keeps its own promoted type, which is that of the result
+        ExpressionKind::ShiftLeft(e1, e2) => value(e1)?.wrapping_shl(value(e2)? as u32),
+        ExpressionKind::ShiftRight(e1, e2) if t.is_unsigned() => {
+            (value(e1)? as u64).wrapping_shr(value(e2)? as u32) as i64
+        }
+        ExpressionKind::ShiftRight(e1, e2) => value(e1)?.wrapping_shr(value(e2)? as u32),
+        ExpressionKind::And(e1, e2) => (value(e1)? != 0 && value(e2)? != 0) as i64,
+        ExpressionKind::Or(e1, e2) => (value(e1)? != 0 || value(e2)? != 0) as i64,
+        ExpressionKind::Equal(e1, e2) => compare(e1, e2, std::cmp::Ordering::is_eq)?,
+        ExpressionKind::NotEqual(e1, e2) => compare(e1, e2, std::cmp::Ordering::is_ne)?,
+        ExpressionKind::LessThan(e1, e2) => compare(e1, e2, std::cmp::Ordering::is_lt)?,
+        ExpressionKind::LessThanOrEqual(e1, e2) => compare(e1, e2, std::cmp::Ordering::is_le)?,
+        ExpressionKind::GreaterThan(e1, e2) => compare(e1, e2, std::cmp::Ordering::is_gt)?,
+        ExpressionKind::GreaterThanOrEqual(e1, e2) => {
+            compare(e1, e2, std::cmp::Ordering::is_ge)?
+        }
+        // only the selected operand is evaluated, but it takes the type of the whole
+        ExpressionKind::Conditional(cond, exp, alt) => {
+            if value(cond)? != 0 {
+                value(exp)?
+            } else {
+                value(alt)?
+            }
+        }
+        _ => return None,
+    };
+    Some(convert(result, &t))
+}
+
+// evaluates an arithmetic constant expression as a floating point value
+pub fn evaluate_floating(expr: &Expression) -> Option<f64> {
+    let env = Constants::default();
+    let t = expr.typ(&env).ok()?;
+    if t.is_integer() {
+        let i = integer(expr, &env)?;
+        return Some(if t.is_unsigned() { i as u64 as f64 } else { i as f64 });
+    }
+    match &expr.kind {
+        ExpressionKind::FloatLiteral(f, _) => Some(*f),
+        ExpressionKind::Cast(t, e) => match t.unqualified() {
+            Type::Float => evaluate_floating(e).map(|f| f as f32 as f64),
+            Type::Double => evaluate_floating(e),
+            _ => None,
+        },
+        ExpressionKind::Minus(e) => evaluate_floating(e).map(|f| -f),
+        ExpressionKind::Add(e1, e2) => Some(evaluate_floating(e1)? + evaluate_floating(e2)?),
+        ExpressionKind::Subtract(e1, e2) => Some(evaluate_floating(e1)? - evaluate_floating(e2)?),
+        ExpressionKind::Multiply(e1, e2) => Some(evaluate_floating(e1)? * evaluate_floating(e2)?),
+        ExpressionKind::Divide(e1, e2) => Some(evaluate_floating(e1)? / evaluate_floating(e2)?),
+        ExpressionKind::Conditional(cond, exp, alt) => {
+            if evaluate_floating(cond)? != 0.0 {
+                evaluate_floating(exp)
+            } else {
+                evaluate_floating(alt)
+            }
+        }
+        _ => None,
+    }
+}
+
+// the bits a constant expression occupies once converted to type t
+pub fn evaluate_as(expr: &Expression, t: &Type) -> Option<i64> {
+    match t.unqualified() {
+        Type::Float => evaluate_floating(expr).map(|f| (f as f32).to_bits() as i64),
+        Type::Double => evaluate_floating(expr).map(|f| f.to_bits() as i64),
+        _ => evaluate(expr)
+            .or_else(|| evaluate_floating(expr).map(|f| from_floating(f, t)))
+            .map(|i| convert(i, t)),
+    }
+}
+
+// the integer of type t a floating value is truncated to
+fn from_floating(f: f64, t: &Type) -> i64 {
+    if t.is_unsigned() {
+        f as u64 as i64
+    } else {
+        f as i64
+    }
+}
+
+// the value of i once converted to type t
+fn convert(i: i64, t: &Type) -> i64 {
+    match t.unqualified() {
+        Type::Char | Type::SChar => i as i8 as i64,
+        Type::UChar => i as u8 as i64,
+        Type::Short => i as i16 as i64,
+        Type::UShort => i as u16 as i64,
+        Type::Int => i as i32 as i64,
+        Type::UInt => i as u32 as i64,
+        _ => i,
+    }
+}
+
+// the symbol and offset in bytes of an address constant, like &x, &a[2] or f, where symbol
+// gives that of the variables and functions with static storage
+pub fn evaluate_address<E: TypeEnv>(
+    expr: &Expression,
+    env: &E,
+    symbol: &dyn Fn(&Identifier) -> Option<String>,
+) -> Option<(String, i64)> {
+    match &expr.kind {
+        ExpressionKind::Identifier(_) => {
+            let t = expr.object_type(env).ok()?;
+            if t.is_array() || t.is_function() {
+                object_address(expr, env, symbol)
+            } else {
+                None
+            }
+        }
+        ExpressionKind::AddressOf(e) => object_address(e, env, symbol),
+        ExpressionKind::Cast(t, e) if t.is_pointer() => evaluate_address(e, env, symbol),
+        ExpressionKind::Add(e1, e2) | ExpressionKind::Subtract(e1, e2) => {
+            let pointee = expr.typ(env).ok()?.decay();
+            let step = env.layouts().size(pointee.pointee()?) as i64;
+            let (pointer, offset) = match evaluate_address(e1, env, symbol) {
+                Some(address) => (address, evaluate(e2)?),
+                None if matches!(expr.kind, ExpressionKind::Add(_, _)) => {
+                    (evaluate_address(e2, env, symbol)?, evaluate(e1)?)
+                }
+                None => return None,
+            };
+            let offset = match expr.kind {
+                ExpressionKind::Subtract(_, _) => offset.wrapping_neg(),
+                _ => offset,
+            };
+            Some((pointer.0, pointer.1.wrapping_add(offset.wrapping_mul(step))))
+        }
+        _ => None,
+    }
+}
+
+// the address of an object with static storage, or of a part of it
+fn object_address<E: TypeEnv>(
+    expr: &Expression,
+    env: &E,
+    symbol: &dyn Fn(&Identifier) -> Option<String>,
+) -> Option<(String, i64)> {
+    match &expr.kind {
+        ExpressionKind::Identifier(id) => symbol(id).map(|s| (s, 0)),
+        ExpressionKind::Deref(e) => evaluate_address(e, env, symbol),
+        ExpressionKind::Member(e, field) => {
+            let Type::Struct(name) = e.typ(env).ok()?.unqualified().clone() else {
+                return None;
+            };
+            let offset = env.layouts().get(&name)?.fields.get(field)?.1;
+            let (s, base) = object_address(e, env, symbol)?;
+            Some((s, base + offset as i64))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexing::lex;
+    use crate::parsing::parse_constant;
+
+    fn parse(text: &str) -> Expression {
+        parse_constant(&lex(text).unwrap()).unwrap()
+    }
+
+    fn eval(text: &str) -> Option<i64> {
+        evaluate(&parse(text))
+    }
+
+    #[test]
+    fn usual_arithmetic_conversions() {
+        assert_eq!(eval("18446744073709551615UL / 2"), Some(i64::MAX));
+        assert_eq!(eval("-1 < 0u"), Some(0));
+        assert_eq!(eval("-1 > 0u"), Some(1));
+        assert_eq!(eval("-1 < 0"), Some(1));
+        assert_eq!(eval("0xFFFFFFFFu + 1"), Some(0));
+        assert_eq!(eval("0xFFFFFFFFu + 1L"), Some(0x100000000));
+        assert_eq!(eval("-1 / 2u"), Some(0x7FFFFFFF));
+    }
+
+    #[test]
+    fn results_have_the_type_of_the_operation() {
+        assert_eq!(eval("2147483647 + 1"), Some(i32::MIN as i64));
+        assert_eq!(eval("-1 >> 1"), Some(-1));
+        assert_eq!(eval("0xFFFFFFFF >> 1"), Some(0x7FFFFFFF));
+        assert_eq!(eval("1 ? -1 : 0u"), Some(0xFFFFFFFF));
+        assert_eq!(eval("(unsigned char)300"), Some(44));
+        assert_eq!(eval("(short)65535"), Some(-1));
+        assert_eq!(eval("'\\377'"), Some(-1));
+    }
+
+    #[test]
+    fn logical_operators() {
+        assert_eq!(eval("!0 + !5"), Some(1));
+        assert_eq!(eval("2 && 0 || 3"), Some(1));
+        assert_eq!(eval("0 ? 1 / 0 : 4"), Some(4));
+    }
+
+    #[test]
+    fn not_constant() {
+        assert_eq!(eval("1 / 0"), None);
+        assert_eq!(eval("5 % 0u"), None);
+        assert_eq!(eval("1.5 + 1"), None);
+        assert_eq!(eval("x + 1"), None);
+    }
+
+    #[test]
+    fn floating() {
+        assert_eq!(evaluate_floating(&parse("1.5 + 1")), Some(2.5));
+        assert_eq!(evaluate_floating(&parse("(float)0.1")), Some(0.1f32 as f64));
+        assert_eq!(evaluate_floating(&parse("0xFFFFFFFFu")), Some(4294967295.0));
+        assert_eq!(evaluate_as(&parse("3.7"), &Type::Int), Some(3));
+        assert_eq!(evaluate_as(&parse("-1"), &Type::UChar), Some(255));
+        assert_eq!(evaluate_as(&parse("2"), &Type::Double), Some(2f64.to_bits() as i64));
+    }
+}
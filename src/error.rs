@@ -1,22 +1,88 @@
+use combine::easy::{self, Info};
 use std::error::Error;
 use std::fmt;
-use combine::stream::state::SourcePosition;
+
+// where something was written in the original sources
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+// a piece of the preprocessed text, as byte offsets, and where it starts in the original sources
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub location: Location,
+}
+
+impl Span {
+    // from the start of this span to the end of another, further one
+    pub fn to(&self, end: &Span) -> Span {
+        Span {
+            start: self.start,
+            end: end.end,
+            location: self.location.clone(),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum CompilerError {
-    Parser(String, usize),
-    Lexer(String, SourcePosition),
-    Validation(String),
+    Preprocessor(String, Location),
+    Parser(String, Location),
+    Lexer(String, Location),
+    Validation(String, Location),
 }
 
 impl fmt::Display for CompilerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            CompilerError::Parser(e, pos) => write!(f, "Parser Error line {}: {}", pos, e),
-            CompilerError::Lexer(e, pos) => write!(f, "Lexer Error position {}: {}", pos.line, e),
-            CompilerError::Validation(e) => write!(f, "Validation Error: {}", e),
+            CompilerError::Preprocessor(e, loc) => write!(f, "{}: Preprocessor Error: {}", loc, e),
+            CompilerError::Parser(e, loc) => write!(f, "{}: Parser Error: {}", loc, e),
+            CompilerError::Lexer(e, loc) => write!(f, "{}: Lexer Error: {}", loc, e),
+            CompilerError::Validation(e, loc) => write!(f, "{}: Validation Error: {}", loc, e),
         }
     }
 }
 
 impl Error for CompilerError {}
+
+// what a parser found and what it would have accepted instead, as in
+// "unexpected `x`, expected `;` or `,`"
+pub fn describe<T: fmt::Display, R: fmt::Display>(errors: &[easy::Error<T, R>]) -> String {
+    let quoted = |info: &Info<T, R>| match info {
+        Info::Token(_) | Info::Range(_) => format!("`{}`", info),
+        _ => info.to_string(),
+    };
+    let mut unexpected = Vec::new();
+    let mut expected = Vec::new();
+    let mut messages = Vec::new();
+    for error in errors {
+        match error {
+            easy::Error::Unexpected(info) => unexpected.push(quoted(info)),
+            easy::Error::Expected(info) => expected.push(quoted(info)),
+            easy::Error::Message(info) => messages.push(info.to_string()),
+            easy::Error::Other(e) => messages.push(e.to_string()),
+        }
+    }
+    expected.dedup();
+
+    let mut parts: Vec<_> = unexpected.into_iter().map(|u| format!("unexpected {}", u)).collect();
+    if let Some((last, rest)) = expected.split_last() {
+        parts.push(match rest {
+            [] => format!("expected {}", last),
+            _ => format!("expected {} or {}", rest.join(", "), last),
+        });
+    }
+    parts.extend(messages);
+    parts.join(", ")
+}
@@ -0,0 +1,177 @@
+use crate::ast::*;
+use crate::error::{CompilerError, Span};
+use indexmap::IndexMap;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub struct StructLayout {
+    pub size: usize,
+    pub align: usize,
+    pub fields: IndexMap<Identifier, (Type, usize)>,
+}
+
+// sizes, alignments and field offsets following the System V x86-64 ABI
+#[derive(Debug, Default)]
+pub struct Layouts {
+    structs: HashMap<Identifier, StructLayout>,
+}
+
+impl Layouts {
+    pub fn new(structs: &[StructDef]) -> Result<Self, CompilerError> {
+        let va_list_tag = StructDef::new(
+            VA_LIST_TAG.to_string(),
+            vec![
+                (Type::UInt, "gp_offset".to_string()),
+                (Type::UInt, "fp_offset".to_string()),
+                (Type::Pointer(Box::new(Type::Void)), "overflow_arg_area".to_string()),
+                (Type::Pointer(Box::new(Type::Void)), "reg_save_area".to_string()),
+            ],
+            Span::default(),
+        );
+
+        let mut layouts = Self::default();
+        for def in std::iter::once(&va_list_tag).chain(structs.iter()) {
+            layouts.add(def)?;
+        }
+        Ok(layouts)
+    }
+
+    // lays out a struct, which may only use those added before it
+    pub fn add(&mut self, def: &StructDef) -> Result<(), CompilerError> {
+        if self.structs.contains_key(&def.name) {
+            return Err(CompilerError::Validation(
+                format!("Redefinition of struct {}", def.name),
+                def.span.location.clone(),
+            ));
+        }
+
+        let mut fields = IndexMap::new();
+        let mut size: usize = 0;
+        let mut align = 1;
+        for (t, id) in def.fields.iter() {
+            if !self.is_complete(t) {
+                return Err(CompilerError::Validation(
+                    format!("Field {} of struct {} has incomplete type", id, def.name),
+                    def.span.location.clone(),
+                ));
+            }
+            let field_align = self.align(t);
+            let offset = size.next_multiple_of(field_align);
+            if fields.insert(id.clone(), (t.clone(), offset)).is_some() {
+                return Err(CompilerError::Validation(
+                    format!("Duplicate field {} in struct {}", id, def.name),
+                    def.span.location.clone(),
+                ));
+            }
+            size = offset + self.size(t);
+            align = align.max(field_align);
+        }
+
+        self.structs.insert(
+            def.name.clone(),
+            StructLayout {
+                size: size.next_multiple_of(align),
+                align,
+                fields,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn get(&self, name: &Identifier) -> Option<&StructLayout> {
+        self.structs.get(name)
+    }
+
+    pub fn is_complete(&self, t: &Type) -> bool {
+        match t.unqualified() {
+            Type::Struct(name) => self.structs.contains_key(name),
+            Type::Array(t, _) => self.is_complete(t),
+            Type::Void | Type::Function(_, _, _) => false,
+            _ => true,
+        }
+    }
+
+    pub fn size(&self, t: &Type) -> usize {
+        match t.unqualified() {
+            // void and function pointer arithmetic steps over single bytes, as in GNU C
+            Type::Char | Type::SChar | Type::UChar | Type::Void | Type::Function(_, _, _) => 1,
+            Type::Short | Type::UShort => 2,
+            Type::Int | Type::UInt | Type::Float => 4,
+            Type::Long | Type::ULong | Type::LongLong | Type::ULongLong | Type::Double => 8,
+            Type::Pointer(_) => 8,
+            Type::Array(t, Length::Constant(len)) => self.size(t) * len,
+            Type::Array(_, Length::Expression(_)) => {
+                unreachable!("array lengths are computed when lowering")
+            }
+            Type::Struct(name) => self.structs[name].size,
+            Type::Qualified(_, _) => unreachable!("qualifiers are never nested"),
+        }
+    }
+
+    pub fn align(&self, t: &Type) -> usize {
+        match t.unqualified() {
+            Type::Array(t, _) => self.align(t),
+            Type::Struct(name) => self.structs[name].align,
+            _ => self.size(t),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexing::lex;
+    use crate::lowering::lower;
+    use crate::parsing::parse;
+
+    // array lengths are only known once lowered
+    fn layouts(source: &str) -> Result<Layouts, CompilerError> {
+        let mut program = parse(&lex(source).unwrap()).unwrap();
+        lower(&mut program)?;
+        Layouts::new(&program.structs)
+    }
+
+    fn offsets(layout: &StructLayout) -> Vec<(&str, usize)> {
+        layout.fields.iter().map(|(id, (_, offset))| (id.as_str(), *offset)).collect()
+    }
+
+    #[test]
+    fn fields_are_aligned() {
+        let layouts = layouts("struct S { char c; int i; char d; long l; short s; };").unwrap();
+        let s = layouts.get(&"S".to_string()).unwrap();
+        assert_eq!(offsets(s), [("c", 0), ("i", 4), ("d", 8), ("l", 16), ("s", 24)]);
+        assert_eq!((s.size, s.align), (32, 8));
+    }
+
+    #[test]
+    fn nested_structs_and_arrays() {
+        let source = "struct In { char c[3]; short s; };
+            struct Out { char c; struct In in[2]; double d; };";
+        let layouts = layouts(source).unwrap();
+        let inner = layouts.get(&"In".to_string()).unwrap();
+        assert_eq!((inner.size, inner.align), (6, 2));
+        let outer = layouts.get(&"Out".to_string()).unwrap();
+        assert_eq!(offsets(outer), [("c", 0), ("in", 2), ("d", 16)]);
+        assert_eq!(layouts.size(&Type::Struct("Out".to_string())), 24);
+    }
+
+    #[test]
+    fn scalars() {
+        let layouts = Layouts::default();
+        let sizes: Vec<_> = [Type::Char, Type::Short, Type::Int, Type::Long, Type::Float]
+            .iter()
+            .map(|t| (layouts.size(t), layouts.align(t)))
+            .collect();
+        assert_eq!(sizes, [(1, 1), (2, 2), (4, 4), (8, 8), (4, 4)]);
+        assert_eq!(layouts.size(&Type::Array(Box::new(Type::Int), Length::Constant(3))), 12);
+        assert!(!layouts.is_complete(&Type::Struct("Missing".to_string())));
+    }
+
+    #[test]
+    fn invalid_structs() {
+        assert!(layouts("struct S { int x; }; struct S { int x; };").is_err());
+        assert!(layouts("struct S { int x; long x; };").is_err());
+        assert!(layouts("struct S { struct S s; };").is_err());
+        assert!(layouts("struct S { struct S *next; };").is_ok());
+    }
+}
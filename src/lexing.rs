@@ -1,7 +1,15 @@
-use crate::error::CompilerError;
-use combine::char::{alpha_num, digit, spaces, string};
-use combine::{attempt, optional, choice, eof, many1, sep_end_by1, token, Parser};
-use combine::stream::state::State;
+use crate::error::{describe, CompilerError, Location, Span};
+use combine::char::{alpha_num, digit, hex_digit, letter, oct_digit, space, string};
+use combine::parser::item::one_of;
+use combine::{
+    attempt, between, choice, count_min_max, eof, many, many1, not_followed_by, optional, position,
+    satisfy, sep_end_by1, skip_many, token, ParseError, Parser, Stream,
+};
+use combine::error::StreamError;
+use combine::stream::state::{SourcePosition, State};
+use combine::stream::StreamErrorFor;
+use std::fmt;
+use std::num::IntErrorKind;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
@@ -11,9 +19,26 @@ pub enum Token {
     CloseParen,
     Semicolon,
     Int,
+    Char,
+    Void,
+    Float,
+    Double,
+    Short,
+    Long,
+    Signed,
+    Unsigned,
+    Struct,
+    Static,
+    Extern,
+    Const,
+    Volatile,
     Return,
     Identifier(String),
-    Integer(usize),
+    Integer(usize, IntegerFormat),
+    FloatLiteral(f32),
+    DoubleLiteral(f64),
+    CharLiteral(u8),
+    StringLiteral(Vec<u8>),
     Minus,
     BinaryNot,
     LogicalNot,
@@ -34,6 +59,12 @@ pub enum Token {
     AssignMinus,
     AssignMultiply,
     AssignDivide,
+    AssignModulo,
+    AssignBitwiseAnd,
+    AssignBitwiseOr,
+    AssignBitwiseXor,
+    AssignShiftLeft,
+    AssignShiftRight,
     If,
     Else,
     Colon,
@@ -43,64 +74,600 @@ pub enum Token {
     Do,
     Break,
     Continue,
+    Switch,
+    Case,
+    Default,
+    Goto,
+    Sizeof,
+    Alignof,
+    VaList,
+    VaStart,
+    VaArg,
+    VaEnd,
+    VaCopy,
     Comma,
+    BitwiseAnd,
+    BitwiseOr,
+    BitwiseXor,
+    ShiftLeft,
+    ShiftRight,
+    Modulo,
+    OpenBracket,
+    CloseBracket,
+    Dot,
+    Arrow,
+    Ellipsis,
 }
 
-pub fn lex(text: &str) -> Result<Vec<Token>, CompilerError> {
-    let mut lexer = optional(spaces())
+// tokens as they are spelled, for error messages
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let spelling = match self {
+            Token::Identifier(name) => return write!(f, "{}", name),
+            Token::Integer(value, _) => return write!(f, "{}", value),
+            Token::FloatLiteral(value) => return write!(f, "{}", value),
+            Token::DoubleLiteral(value) => return write!(f, "{}", value),
+            Token::CharLiteral(c) => return write!(f, "'{}'", (*c as char).escape_default()),
+            Token::StringLiteral(s) => {
+                return write!(f, "\"{}\"", String::from_utf8_lossy(s).escape_default())
+            }
+            Token::OpenBrace => "{",
+            Token::CloseBrace => "}",
+            Token::OpenParen => "(",
+            Token::CloseParen => ")",
+            Token::Semicolon => ";",
+            Token::Int => "int",
+            Token::Char => "char",
+            Token::Void => "void",
+            Token::Float => "float",
+            Token::Double => "double",
+            Token::Short => "short",
+            Token::Long => "long",
+            Token::Signed => "signed",
+            Token::Unsigned => "unsigned",
+            Token::Struct => "struct",
+            Token::Static => "static",
+            Token::Extern => "extern",
+            Token::Const => "const",
+            Token::Volatile => "volatile",
+            Token::Return => "return",
+            Token::Minus => "-",
+            Token::BinaryNot => "~",
+            Token::LogicalNot => "!",
+            Token::Add => "+",
+            Token::Multiply => "*",
+            Token::Divide => "/",
+            Token::And => "&&",
+            Token::Or => "||",
+            Token::Equal => "==",
+            Token::NotEqual => "!=",
+            Token::LessThan => "<",
+            Token::LessThanOrEqual => "<=",
+            Token::GreaterThan => ">",
+            Token::GreaterThanOrEqual => ">=",
+            Token::Assign => "=",
+            Token::Increment => "++",
+            Token::Decrement => "--",
+            Token::AssignAdd => "+=",
+            Token::AssignMinus => "-=",
+            Token::AssignMultiply => "*=",
+            Token::AssignDivide => "/=",
+            Token::AssignModulo => "%=",
+            Token::AssignBitwiseAnd => "&=",
+            Token::AssignBitwiseOr => "|=",
+            Token::AssignBitwiseXor => "^=",
+            Token::AssignShiftLeft => "<<=",
+            Token::AssignShiftRight => ">>=",
+            Token::If => "if",
+            Token::Else => "else",
+            Token::Colon => ":",
+            Token::QuestionMark => "?",
+            Token::For => "for",
+            Token::While => "while",
+            Token::Do => "do",
+            Token::Break => "break",
+            Token::Continue => "continue",
+            Token::Switch => "switch",
+            Token::Case => "case",
+            Token::Default => "default",
+            Token::Goto => "goto",
+            Token::Sizeof => "sizeof",
+            Token::Alignof => "_Alignof",
+            Token::VaList => "va_list",
+            Token::VaStart => "va_start",
+            Token::VaArg => "va_arg",
+            Token::VaEnd => "va_end",
+            Token::VaCopy => "va_copy",
+            Token::Comma => ",",
+            Token::BitwiseAnd => "&",
+            Token::BitwiseOr => "|",
+            Token::BitwiseXor => "^",
+            Token::ShiftLeft => "<<",
+            Token::ShiftRight => ">>",
+            Token::Modulo => "%",
+            Token::OpenBracket => "[",
+            Token::CloseBracket => "]",
+            Token::Dot => ".",
+            Token::Arrow => "->",
+            Token::Ellipsis => "...",
+        };
+        write!(f, "{}", spelling)
+    }
+}
+
+// every token comes with its span in the text and the place it was written at, according
+// to the line markers left by the preprocessor
+pub fn lex(text: &str) -> Result<Vec<(Token, Span)>, CompilerError> {
+    let mut lexer = whitespace()
         .with(sep_end_by1::<Vec<_>, _, _>(
-            choice((
+            (
+                position(),
                 choice((
-                    attempt(string("&&").map(|_| Token::And)),
-                    attempt(string("||").map(|_| Token::Or)),
-                    attempt(string("==").map(|_| Token::Equal)),
-                    attempt(string("!=").map(|_| Token::NotEqual)),
-                    attempt(string("<=").map(|_| Token::LessThanOrEqual)),
-                    attempt(string(">=").map(|_| Token::GreaterThanOrEqual)),
-                    attempt(string("int").map(|_| Token::Int)),
-                    attempt(string("return").map(|_| Token::Return)),
-                    attempt(string("if").map(|_| Token::If)),
-                    attempt(string("else").map(|_| Token::Else)),
-                    attempt(string("for").map(|_| Token::For)),
-                    attempt(string("while").map(|_| Token::While)),
-                    attempt(string("do").map(|_| Token::Do)),
-                    attempt(string("break").map(|_| Token::Break)),
-                    attempt(string("continue").map(|_| Token::Continue)),
-                    attempt(string("++").map(|_| Token::Increment)),
-                    attempt(string("--").map(|_| Token::Decrement)),
-                    attempt(string("+=").map(|_| Token::AssignAdd)),
-                    attempt(string("-=").map(|_| Token::AssignMinus)),
-                    attempt(string("*=").map(|_| Token::AssignMultiply)),
-                    attempt(string("/=").map(|_| Token::AssignDivide)),
+                    choice((
+                        attempt(string("...").map(|_| Token::Ellipsis)),
+                        attempt(string("<<=").map(|_| Token::AssignShiftLeft)),
+                        attempt(string(">>=").map(|_| Token::AssignShiftRight)),
+                        attempt(string("<<").map(|_| Token::ShiftLeft)),
+                        attempt(string(">>").map(|_| Token::ShiftRight)),
+                        attempt(string("&&").map(|_| Token::And)),
+                        attempt(string("||").map(|_| Token::Or)),
+                        attempt(string("==").map(|_| Token::Equal)),
+                        attempt(string("!=").map(|_| Token::NotEqual)),
+                        attempt(string("<=").map(|_| Token::LessThanOrEqual)),
+                        attempt(string(">=").map(|_| Token::GreaterThanOrEqual)),
+                        attempt(string("->").map(|_| Token::Arrow)),
+                        attempt(string("++").map(|_| Token::Increment)),
+                        attempt(string("--").map(|_| Token::Decrement)),
+                        attempt(string("+=").map(|_| Token::AssignAdd)),
+                        attempt(string("-=").map(|_| Token::AssignMinus)),
+                        attempt(string("*=").map(|_| Token::AssignMultiply)),
+                        attempt(string("/=").map(|_| Token::AssignDivide)),
+                        attempt(string("%=").map(|_| Token::AssignModulo)),
+                        attempt(string("&=").map(|_| Token::AssignBitwiseAnd)),
+                        attempt(string("|=").map(|_| Token::AssignBitwiseOr)),
+                        attempt(string("^=").map(|_| Token::AssignBitwiseXor)),
+                    )),
+                    attempt(floating_literal()),
+                    choice((
+                        token('<').map(|_| Token::LessThan),
+                        token('>').map(|_| Token::GreaterThan),
+                        token('{').map(|_| Token::OpenBrace),
+                        token('}').map(|_| Token::CloseBrace),
+                        token('(').map(|_| Token::OpenParen),
+                        token(')').map(|_| Token::CloseParen),
+                        token(';').map(|_| Token::Semicolon),
+                        token('-').map(|_| Token::Minus),
+                        token('!').map(|_| Token::LogicalNot),
+                        token('~').map(|_| Token::BinaryNot),
+                        token('+').map(|_| Token::Add),
+                        token('*').map(|_| Token::Multiply),
+                        token('/').map(|_| Token::Divide),
+                        token('=').map(|_| Token::Assign),
+                        token(':').map(|_| Token::Colon),
+                        token(',').map(|_| Token::Comma),
+                        token('?').map(|_| Token::QuestionMark),
+                        token('&').map(|_| Token::BitwiseAnd),
+                        token('|').map(|_| Token::BitwiseOr),
+                        token('^').map(|_| Token::BitwiseXor),
+                        token('%').map(|_| Token::Modulo),
+                        token('[').map(|_| Token::OpenBracket),
+                        token(']').map(|_| Token::CloseBracket),
+                        token('.').map(|_| Token::Dot),
+                    )),
+                    integer_literal(),
+                    between(
+                        token('\''),
+                        token('\''),
+                        choice((
+                            escape_sequence(),
+                            satisfy(|c| c != '\'' && c != '\\' && c != '\n').map(|c: char| c as u8),
+                        )),
+                    )
+                    .map(Token::CharLiteral),
+                    between(
+                        token('"'),
+                        token('"'),
+                        many::<Vec<_>, _>(choice((
+                            escape_sequence().map(|c| vec![c]),
+                            satisfy(|c| c != '"' && c != '\\' && c != '\n')
+                                .map(|c: char| c.to_string().into_bytes()),
+                        ))),
+                    )
+                    .map(|s| Token::StringLiteral(s.concat())),
+                    (letter().or(token('_')), many::<String, _>(alpha_num().or(token('_'))))
+                        .map(|(first, rest)| keyword_or_identifier(format!("{}{}", first, rest))),
                 )),
-                choice((
-                    token('<').map(|_| Token::LessThan),
-                    token('>').map(|_| Token::GreaterThan),
-                    token('{').map(|_| Token::OpenBrace),
-                    token('}').map(|_| Token::CloseBrace),
-                    token('(').map(|_| Token::OpenParen),
-                    token(')').map(|_| Token::CloseParen),
-                    token(';').map(|_| Token::Semicolon),
-                    token('-').map(|_| Token::Minus),
-                    token('!').map(|_| Token::LogicalNot),
-                    token('~').map(|_| Token::BinaryNot),
-                    token('+').map(|_| Token::Add),
-                    token('*').map(|_| Token::Multiply),
-                    token('/').map(|_| Token::Divide),
-                    token('=').map(|_| Token::Assign),
-                    token(':').map(|_| Token::Colon),
-                    token(',').map(|_| Token::Comma),
-                    token('?').map(|_| Token::QuestionMark),
-                )),
-                many1::<String, _>(digit()).map(|i| Token::Integer(i.parse().unwrap())),
-                many1::<String, _>(alpha_num()).map(|id| Token::Identifier(id)),
-            )),
-            spaces(),
+                position(),
+            ),
+            whitespace(),
         ))
         .skip(eof());
 
+    let lines = LineMap::new(text);
     match lexer.easy_parse(State::new(text)) {
-        Ok(tokens) => Ok(tokens.0),
-        Err(e) => Err(CompilerError::Lexer(format!("{:?}", e.errors), e.position)),
+        Ok((tokens, _)) => Ok(tokens
+            .into_iter()
+            .map(|(start, token, end)| (token, lines.span(start, end)))
+            .collect()),
+        Err(e) => Err(CompilerError::Lexer(describe(&e.errors), lines.locate(e.position))),
+    }
+}
+
+// where the lines of a text start and its line markers, as (line of the marker, line it
+// announces, file)
+struct LineMap<'a> {
+    text: &'a str,
+    starts: Vec<usize>,
+    markers: Vec<(usize, usize, String)>,
+}
+
+impl<'a> LineMap<'a> {
+    fn new(text: &'a str) -> Self {
+        LineMap {
+            text,
+            starts: std::iter::once(0)
+                .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+                .collect(),
+            markers: text
+                .lines()
+                .enumerate()
+                .filter_map(|(i, line)| {
+                    let (line_number, file) = parse_line_marker(line)?;
+                    Some((i + 1, line_number, file))
+                })
+                .collect(),
+        }
+    }
+
+    fn span(&self, start: SourcePosition, end: SourcePosition) -> Span {
+        Span {
+            start: self.offset(start),
+            end: self.offset(end),
+            location: self.locate(start),
+        }
+    }
+
+    // positions count characters, spans bytes
+    fn offset(&self, position: SourcePosition) -> usize {
+        let start = self.starts[position.line as usize - 1];
+        self.text[start..]
+            .char_indices()
+            .nth(position.column as usize - 1)
+            .map_or(self.text.len(), |(i, _)| start + i)
+    }
+
+    fn locate(&self, position: SourcePosition) -> Location {
+        let line = position.line as usize;
+        let column = position.column as usize;
+        match self.markers.iter().rev().find(|(marker, _, _)| *marker < line) {
+            Some((marker, line_number, file)) => Location {
+                file: file.clone(),
+                line: line_number + line - marker - 1,
+                column,
+            },
+            None => Location {
+                file: String::new(),
+                line,
+                column,
+            },
+        }
+    }
+}
+
+// a `# 12 "file.h"` line, possibly followed by flags
+pub fn parse_line_marker(line: &str) -> Option<(usize, String)> {
+    let rest = line.strip_prefix('#')?.trim_start();
+    let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    let line_number = rest[..digits].parse().ok()?;
+    let quoted = rest[digits..].trim_start().strip_prefix('"')?;
+    let mut file = String::new();
+    let mut chars = quoted.chars();
+    loop {
+        match chars.next()? {
+            '"' => return Some((line_number, file)),
+            '\\' => file.push(chars.next()?),
+            c => file.push(c),
+        }
+    }
+}
+
+// spaces, which only separate tokens, along with line markers
+// comments never get here, the preprocessor replaced them by spaces
+fn whitespace<I>() -> impl Parser<Input = I, Output = ()>
+where
+    I: Stream<Item = char, Position = SourcePosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    // markers take whole lines, any other # is an error
+    let line_marker = (position(), token('#'), many::<String, _>(satisfy(|c| c != '\n')))
+        .and_then(|(start, _, rest): (SourcePosition, _, String)| {
+            match parse_line_marker(&format!("#{}", rest)) {
+                Some(_) if start.column == 1 => Ok(()),
+                _ => Err(StreamErrorFor::<I>::message_static_message("stray # in program")),
+            }
+        });
+    skip_many(choice((space().map(|_| ()), line_marker)))
+}
+
+// the way an integer constant is written, which together with its value decides its type
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct IntegerFormat {
+    pub decimal: bool,
+    pub unsigned: bool, // u suffix
+    pub long: usize,    // number of l in the suffix
+}
+
+// decimal, octal with a leading 0, hexadecimal with 0x or binary with 0b, then an optional
+// suffix made of u and l or ll in any order
+fn integer_literal<I>() -> impl Parser<Input = I, Output = Token>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    let digits = choice((
+        attempt((token('0'), one_of("xX".chars())))
+            .with(many1::<String, _>(hex_digit()))
+            .map(|digits| (digits, 16)),
+        attempt((token('0'), one_of("bB".chars())))
+            .with(many1::<String, _>(one_of("01".chars())))
+            .map(|digits| (digits, 2)),
+        // octal digits are checked when converting, so that 09 is an error and not two tokens
+        token('0')
+            .with(many::<String, _>(digit()))
+            .map(|digits| (format!("0{}", digits), 8)),
+        many1::<String, _>(digit()).map(|digits| (digits, 10)),
+    ))
+    .and_then(|(digits, radix)| match usize::from_str_radix(&digits, radix) {
+        Ok(value) => Ok((value, radix)),
+        Err(e) if *e.kind() == IntErrorKind::PosOverflow => Err(
+            StreamErrorFor::<I>::message_static_message("integer literal is too large"),
+        ),
+        Err(_) => Err(StreamErrorFor::<I>::message_static_message(
+            "invalid digit in octal literal",
+        )),
+    });
+
+    let long = || {
+        choice((
+            attempt(string("ll").or(string("LL"))).map(|_| 2),
+            one_of("lL".chars()).map(|_| 1),
+        ))
+    };
+    let unsigned = || one_of("uU".chars());
+    let suffix = choice((
+        unsigned().with(optional(long())).map(|long| (true, long.unwrap_or(0))),
+        long().and(optional(unsigned())).map(|(long, u)| (u.is_some(), long)),
+    ));
+
+    digits
+        .and(optional(suffix))
+        .skip(not_followed_by(alpha_num().or(token('_'))))
+        .map(|((value, radix), suffix)| {
+            let (unsigned, long) = suffix.unwrap_or((false, 0));
+            Token::Integer(
+                value,
+                IntegerFormat {
+                    decimal: radix == 10,
+                    unsigned,
+                    long,
+                },
+            )
+        })
+}
+
+// decimal floating constants need either a dot or an exponent, a f suffix makes them float
+fn floating_literal<I>() -> impl Parser<Input = I, Output = Token>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    let exponent = || {
+        (
+            one_of("eE".chars()),
+            optional(one_of("+-".chars())),
+            many1::<String, _>(digit()),
+        )
+            .map(|(e, sign, digits)| {
+                format!("{}{}{}", e, sign.map_or(String::new(), String::from), digits)
+            })
+    };
+
+    let mantissa = choice((
+        (many1::<String, _>(digit()), token('.'), many::<String, _>(digit()))
+            .map(|(int, _, frac)| format!("{}.{}", int, frac)),
+        (token('.'), many1::<String, _>(digit())).map(|(_, frac)| format!("0.{}", frac)),
+    ));
+
+    choice((
+        attempt(mantissa.and(optional(exponent()))).map(|(m, e)| m + &e.unwrap_or_default()),
+        (many1::<String, _>(digit()), exponent()).map(|(m, e)| m + &e),
+    ))
+    .and(optional(one_of("fFlL".chars())))
+    .map(|(text, suffix)| match suffix {
+        Some('f') | Some('F') => Token::FloatLiteral(text.parse().unwrap()),
+        _ => Token::DoubleLiteral(text.parse().unwrap()),
+    })
+}
+
+fn escape_sequence<I>() -> impl Parser<Input = I, Output = u8>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    token('\\').with(choice((
+        choice((
+            token('n').map(|_| b'\n'),
+            token('t').map(|_| b'\t'),
+            token('r').map(|_| b'\r'),
+            token('a').map(|_| 0x07),
+            token('b').map(|_| 0x08),
+            token('f').map(|_| 0x0c),
+            token('v').map(|_| 0x0b),
+            token('\\').map(|_| b'\\'),
+            token('\'').map(|_| b'\''),
+            token('"').map(|_| b'"'),
+            token('?').map(|_| b'?'),
+        )),
+        token('x')
+            .with(many1::<String, _>(hex_digit()))
+            .map(|s| s.chars().fold(0u8, |acc, c| {
+                acc.wrapping_mul(16).wrapping_add(c.to_digit(16).unwrap() as u8)
+            })),
+        count_min_max::<String, _>(1, 3, oct_digit())
+            .map(|s| u32::from_str_radix(&s, 8).unwrap() as u8),
+    )))
+}
+
+fn keyword_or_identifier(word: String) -> Token {
+    match word.as_str() {
+        "int" => Token::Int,
+        "char" => Token::Char,
+        "void" => Token::Void,
+        "float" => Token::Float,
+        "double" => Token::Double,
+        "short" => Token::Short,
+        "long" => Token::Long,
+        "signed" => Token::Signed,
+        "unsigned" => Token::Unsigned,
+        "struct" => Token::Struct,
+        "static" => Token::Static,
+        "extern" => Token::Extern,
+        "const" => Token::Const,
+        "volatile" => Token::Volatile,
+        "return" => Token::Return,
+        "if" => Token::If,
+        "else" => Token::Else,
+        "for" => Token::For,
+        "while" => Token::While,
+        "do" => Token::Do,
+        "break" => Token::Break,
+        "continue" => Token::Continue,
+        "switch" => Token::Switch,
+        "case" => Token::Case,
+        "default" => Token::Default,
+        "goto" => Token::Goto,
+        "sizeof" => Token::Sizeof,
+        "_Alignof" => Token::Alignof,
+        // the stdarg.h facilities are built in
+        "va_list" => Token::VaList,
+        "va_start" => Token::VaStart,
+        "va_arg" => Token::VaArg,
+        "va_end" => Token::VaEnd,
+        "va_copy" => Token::VaCopy,
+        _ => Token::Identifier(word),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> Vec<Token> {
+        lex(text).unwrap().into_iter().map(|(t, _)| t).collect()
+    }
+
+    #[test]
+    fn line_markers_locate_tokens() {
+        let lexed = lex("# 1 \"a.c\"\nint\n# 7 \"b.h\" 1\n  x").unwrap();
+        let locations: Vec<_> = lexed.iter().map(|(_, s)| s.location.clone()).collect();
+        let location = |file: &str, line, column| Location {
+            file: file.to_string(),
+            line,
+            column,
+        };
+        assert_eq!(locations, [location("a.c", 1, 1), location("b.h", 7, 3)]);
+    }
+
+    #[test]
+    fn stray_hash_is_an_error() {
+        assert!(lex("int x = 1 # garbage;").is_err());
+        assert!(lex("  # 1 \"a.c\"\nint").is_err());
+        assert!(lex("# include <a.h>\nint").is_err());
+        assert_eq!(tokens("# 3 \"a.c\"\nint"), [Token::Int]);
+    }
+
+    #[test]
+    fn errors_are_described() {
+        let message = |text| match lex(text) {
+            Err(CompilerError::Lexer(message, _)) => message,
+            _ => panic!("{} should not lex", text),
+        };
+        assert_eq!(message("int @"), "unexpected `@`, expected end of input");
+        assert_eq!(message("x # y"), "stray # in program");
+        assert_eq!(Token::AssignShiftLeft.to_string(), "<<=");
+        assert_eq!(Token::StringLiteral(b"a\n".to_vec()).to_string(), "\"a\\n\"");
+    }
+
+    #[test]
+    fn integer_literals() {
+        let format = |decimal, unsigned, long| IntegerFormat {
+            decimal,
+            unsigned,
+            long,
+        };
+        assert_eq!(
+            tokens("42 0x2a 052 0b101010 42u 42L 42ull 42LLU"),
+            [
+                Token::Integer(42, format(true, false, 0)),
+                Token::Integer(42, format(false, false, 0)),
+                Token::Integer(42, format(false, false, 0)),
+                Token::Integer(42, format(false, false, 0)),
+                Token::Integer(42, format(true, true, 0)),
+                Token::Integer(42, format(true, false, 1)),
+                Token::Integer(42, format(true, true, 2)),
+                Token::Integer(42, format(true, true, 2)),
+            ]
+        );
+        assert_eq!(tokens("0"), [Token::Integer(0, format(false, false, 0))]);
+        assert_eq!(
+            tokens("18446744073709551615"),
+            [Token::Integer(usize::MAX, format(true, false, 0))]
+        );
+    }
+
+    #[test]
+    fn invalid_integer_literals() {
+        let message = |text| match lex(text) {
+            Err(CompilerError::Lexer(message, _)) => message,
+            _ => panic!("{} should not lex", text),
+        };
+        assert_eq!(message("18446744073709551616"), "integer literal is too large");
+        assert_eq!(message("0x10000000000000000"), "integer literal is too large");
+        assert_eq!(message("09"), "invalid digit in octal literal");
+        assert!(lex("42lul").is_err());
+        assert!(lex("42abc").is_err());
+    }
+
+    #[test]
+    fn floating_literals() {
+        assert_eq!(
+            tokens("1.5 .5 2. 1e3 1.5e-2 2.5f 1E+2F 3.0L"),
+            [
+                Token::DoubleLiteral(1.5),
+                Token::DoubleLiteral(0.5),
+                Token::DoubleLiteral(2.0),
+                Token::DoubleLiteral(1000.0),
+                Token::DoubleLiteral(0.015),
+                Token::FloatLiteral(2.5),
+                Token::FloatLiteral(100.0),
+                Token::DoubleLiteral(3.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn escape_sequences() {
+        assert_eq!(
+            tokens(r"'a' '\n' '\0' '\'' '\\' '\x41' '\101' '\7'"),
+            [b'a', b'\n', 0, b'\'', b'\\', b'A', b'A', 7]
+                .iter()
+                .map(|&c| Token::CharLiteral(c))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            tokens(r#""a\tb\"\x7f\1234""#),
+            [Token::StringLiteral(b"a\tb\"\x7f\x534".to_vec())]
+        );
+        assert!(lex(r"'\q'").is_err());
     }
 }
@@ -0,0 +1,264 @@
+use crate::ast::*;
+use crate::constant::{evaluate, evaluate_as};
+use crate::error::CompilerError;
+use crate::layout::Layouts;
+use crate::typing::Scope;
+use std::collections::HashMap;
+
+// replaces the operators evaluated at compile time by the constants they stand for
+pub fn lower(program: &mut Program) -> Result<(), CompilerError> {
+    let mut scope = Scope::new(Layouts::new(&[])?);
+    // the arrays of a struct may be sized using the structs defined before it
+    for def in program.structs.iter_mut() {
+        for (t, _) in def.fields.iter_mut() {
+            lower_type(t, &scope)?;
+        }
+        scope.layouts.add(def)?;
+    }
+    for global in program.globals.iter_mut() {
+        lower_type(&mut global.typename, &scope)?;
+        scope
+            .variables[0]
+            .insert(global.name.clone(), global.typename.clone());
+    }
+    for global in program.globals.iter_mut() {
+        if let Some(value) = &mut global.value {
+            lower_expression(value, &scope)?;
+        }
+    }
+
+    for fun in program.funs.iter_mut() {
+        lower_type(&mut fun.return_type, &scope)?;
+        for (t, _) in fun.args.iter_mut() {
+            lower_type(t, &scope)?;
+        }
+        scope.functions.insert(fun.name.clone(), fun.signature());
+        scope.function = Some(fun.name.clone());
+
+        if let Some(statements) = &mut fun.statements {
+            scope.variables.push(HashMap::new());
+            for (t, id) in fun.args.iter() {
+                scope.declare(id, t, &fun.span)?;
+            }
+            for stm in statements.iter_mut() {
+                lower_statement(stm, &mut scope)?;
+            }
+            scope.variables.pop();
+        }
+    }
+    Ok(())
+}
+
+fn lower_statement(statement: &mut Statement, scope: &mut Scope) -> Result<(), CompilerError> {
+    match &mut statement.kind {
+        StatementKind::Declaration(t, id, expr, _) => {
+            lower_type(t, scope)?;
+            scope.declare(id, t, &statement.span)?;
+            if let Some(expr) = expr {
+                lower_expression(expr, scope)?;
+            }
+        }
+        StatementKind::Return(Some(expr)) | StatementKind::Expression(Some(expr)) => {
+            lower_expression(expr, scope)?
+        }
+        StatementKind::If(cond, stm, alt) => {
+            lower_expression(cond, scope)?;
+            lower_statement(stm, scope)?;
+            if let Some(alt) = alt {
+                lower_statement(alt, scope)?;
+            }
+        }
+        StatementKind::Compound(stms) => {
+            scope.variables.push(HashMap::new());
+            for stm in stms.iter_mut() {
+                lower_statement(stm, scope)?;
+            }
+            scope.variables.pop();
+        }
+        StatementKind::For(init, cond, iter, body) => {
+            for expr in init.iter_mut().chain(Some(cond)).chain(iter.iter_mut()) {
+                lower_expression(expr, scope)?;
+            }
+            lower_statement(body, scope)?;
+        }
+        StatementKind::ForDecl(decls, cond, iter, body) => {
+            scope.variables.push(HashMap::new());
+            for stm in decls.iter_mut() {
+                lower_statement(stm, scope)?;
+            }
+            for expr in Some(cond).into_iter().chain(iter.iter_mut()) {
+                lower_expression(expr, scope)?;
+            }
+            lower_statement(body, scope)?;
+            scope.variables.pop();
+        }
+        StatementKind::Switch(cond, body) => {
+            lower_expression(cond, scope)?;
+            lower_statement(body, scope)?;
+            let t = cond.typ(scope)?.promote();
+            if t.is_integer() {
+                convert_cases(body, &t, scope)?;
+            }
+        }
+        StatementKind::While(cond, body)
+        | StatementKind::Do(body, cond)
+        | StatementKind::Case(cond, body) => {
+            lower_expression(cond, scope)?;
+            lower_statement(body, scope)?;
+        }
+        StatementKind::Default(stm) | StatementKind::Labeled(_, stm) => {
+            lower_statement(stm, scope)?
+        }
+        StatementKind::Return(None)
+        | StatementKind::Expression(None)
+        | StatementKind::Goto(_)
+        | StatementKind::Break
+        | StatementKind::Continue => {}
+    }
+    Ok(())
+}
+
+// replaces the case labels of a switch body by their value converted to the promoted type of
+// the controlling expression, the one they are compared in
+fn convert_cases(statement: &mut Statement, t: &Type, scope: &Scope) -> Result<(), CompilerError> {
+    match &mut statement.kind {
+        StatementKind::Case(label, stm) => {
+            // labels which aren't integer constants are left for validation to report
+            if evaluate(label).is_some() && label.typ(scope)?.is_integer() {
+                let value = evaluate_as(label, t).expect("label should be constant");
+                label.kind = ExpressionKind::Literal(value as usize, t.clone());
+            }
+            convert_cases(stm, t, scope)
+        }
+        StatementKind::If(_, stm, alt) => {
+            convert_cases(stm, t, scope)?;
+            match alt {
+                Some(alt) => convert_cases(alt, t, scope),
+                None => Ok(()),
+            }
+        }
+        StatementKind::Compound(stms) => {
+            for stm in stms.iter_mut() {
+                convert_cases(stm, t, scope)?;
+            }
+            Ok(())
+        }
+        StatementKind::Default(stm)
+        | StatementKind::Labeled(_, stm)
+        | StatementKind::For(_, _, _, stm)
+        | StatementKind::ForDecl(_, _, _, stm)
+        | StatementKind::While(_, stm)
+        | StatementKind::Do(stm, _) => convert_cases(stm, t, scope),
+        // nested switches own their cases
+        _ => Ok(()),
+    }
+}
+
+// computes the lengths of the arrays t is made of
+fn lower_type(t: &mut Type, scope: &Scope) -> Result<(), CompilerError> {
+    match t {
+        Type::Pointer(t) | Type::Qualified(t, _) => lower_type(t, scope),
+        Type::Array(t, len) => {
+            lower_type(t, scope)?;
+            if let Length::Expression(e) = len {
+                lower_expression(e, scope)?;
+                let value = evaluate(e).filter(|&i| i >= 0).ok_or_else(|| {
+                    CompilerError::Validation(
+                        "Array size must be a non-negative integer constant".to_string(),
+                        e.span.location.clone(),
+                    )
+                })?;
+                *len = Length::Constant(value as usize);
+            }
+            Ok(())
+        }
+        Type::Function(return_type, params, _) => {
+            for t in std::iter::once(&mut **return_type).chain(params.iter_mut()) {
+                lower_type(t, scope)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn lower_expression(expr: &mut Expression, scope: &Scope) -> Result<(), CompilerError> {
+    // types written in the expression are lowered first, as sizeof may need them
+    match &mut expr.kind {
+        ExpressionKind::SizeOf(t)
+        | ExpressionKind::AlignOf(t)
+        | ExpressionKind::Cast(t, _)
+        | ExpressionKind::VaArg(_, t) => lower_type(t, scope)?,
+        ExpressionKind::SizeOfExpr(e) => lower_expression(e, scope)?,
+        _ => {}
+    }
+    match expr.kind {
+        ExpressionKind::SizeOf(_) | ExpressionKind::SizeOfExpr(_) | ExpressionKind::AlignOf(_) => {
+            // the operand is only type checked, never evaluated
+            let t = expr.typ(scope)?;
+            let value = match &expr.kind {
+                ExpressionKind::SizeOf(operand) => scope.layouts.size(operand),
+                ExpressionKind::SizeOfExpr(e) => scope.layouts.size(&e.object_type(scope)?),
+                ExpressionKind::AlignOf(operand) => scope.layouts.align(operand),
+                _ => unreachable!(),
+            };
+            expr.kind = ExpressionKind::Literal(value, t);
+        }
+        _ => {
+            for e in operands(expr) {
+                lower_expression(e, scope)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn operands(expr: &mut Expression) -> Vec<&mut Expression> {
+    match &mut expr.kind {
+        ExpressionKind::Identifier(_)
+        | ExpressionKind::Literal(_, _)
+        | ExpressionKind::FloatLiteral(_, _)
+        | ExpressionKind::StringLiteral(_)
+        | ExpressionKind::SizeOf(_)
+        | ExpressionKind::SizeOfExpr(_)
+        | ExpressionKind::AlignOf(_) => Vec::new(),
+        ExpressionKind::Minus(e)
+        | ExpressionKind::BinaryNot(e)
+        | ExpressionKind::LogicalNot(e)
+        | ExpressionKind::AddressOf(e)
+        | ExpressionKind::Deref(e)
+        | ExpressionKind::Member(e, _)
+        | ExpressionKind::Cast(_, e)
+        | ExpressionKind::PreIncrement(e)
+        | ExpressionKind::PreDecrement(e)
+        | ExpressionKind::PostIncrement(e)
+        | ExpressionKind::PostDecrement(e)
+        | ExpressionKind::VaStart(e)
+        | ExpressionKind::VaArg(e, _) => vec![e],
+        ExpressionKind::Subtract(e1, e2)
+        | ExpressionKind::Add(e1, e2)
+        | ExpressionKind::Divide(e1, e2)
+        | ExpressionKind::Multiply(e1, e2)
+        | ExpressionKind::Modulo(e1, e2)
+        | ExpressionKind::BitwiseAnd(e1, e2)
+        | ExpressionKind::BitwiseOr(e1, e2)
+        | ExpressionKind::BitwiseXor(e1, e2)
+        | ExpressionKind::ShiftLeft(e1, e2)
+        | ExpressionKind::ShiftRight(e1, e2)
+        | ExpressionKind::And(e1, e2)
+        | ExpressionKind::Or(e1, e2)
+        | ExpressionKind::Equal(e1, e2)
+        | ExpressionKind::NotEqual(e1, e2)
+        | ExpressionKind::LessThan(e1, e2)
+        | ExpressionKind::LessThanOrEqual(e1, e2)
+        | ExpressionKind::GreaterThan(e1, e2)
+        | ExpressionKind::GreaterThanOrEqual(e1, e2)
+        | ExpressionKind::Assignment(e1, e2)
+        | ExpressionKind::Comma(e1, e2) => vec![e1, e2],
+        ExpressionKind::CompoundAssignment(_, e1, e2) => vec![e1, e2],
+        ExpressionKind::Conditional(cond, exp, alt) => vec![cond, exp, alt],
+        ExpressionKind::FunCall(callee, args) => std::iter::once(&mut **callee)
+            .chain(args.iter_mut())
+            .collect(),
+    }
+}
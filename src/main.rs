@@ -3,30 +3,75 @@ extern crate combine;
 extern crate indexmap;
 
 mod codegen;
+mod constant;
 mod error;
+mod layout;
 mod lexing;
+mod lowering;
 mod parsing;
+mod preprocessing;
+mod typing;
 mod validation;
 mod ast;
 
 use codegen::*;
 use lexing::*;
+use lowering::*;
 use ast::*;
 use parsing::*;
+use preprocessing::*;
 use validation::*;
 use std::env;
 use std::error::Error;
-use std::fs::read_to_string;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = env::args().skip(1).collect();
-    for file in args {
-        let text = read_to_string(&file)?;
-        let tokens: Vec<Token> = lex(&text)?;
+// errors are printed as file:line:column: message rather than in their debug form
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let mut files = Vec::new();
+    let mut options = Options::default();
+    let mut preprocess_only = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-E" {
+            preprocess_only = true;
+            continue;
+        }
+        // options take their value either attached or as the next argument
+        let option = ["-I", "-D", "-U"].iter().find(|o| arg.starts_with(*o));
+        let Some(option) = option else {
+            files.push(arg);
+            continue;
+        };
+        let value = match &arg[2..] {
+            "" => args.next().ok_or(format!("{} expects a value", option))?,
+            value => value.to_string(),
+        };
+        match *option {
+            "-I" => options.include_dirs.push(PathBuf::from(value)),
+            "-D" => options.macros.push(MacroOption::Define(value)),
+            _ => options.macros.push(MacroOption::Undefine(value)),
+        }
+    }
+
+    for file in files {
+        let text = preprocess(Path::new(&file), &options)?;
+        if preprocess_only {
+            print!("{}", text);
+            continue;
+        }
+        let tokens: Vec<(Token, _)> = lex(&text)?;
         println!("{:#?}", tokens);
-        let ast: Program = parse(&tokens)?;
+        let mut ast: Program = parse(&tokens)?;
+        lower(&mut ast)?;
         println!("{:#?}", ast);
         validate(&ast)?;
         let output_path = format!(
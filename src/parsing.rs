@@ -1,78 +1,497 @@
 use crate::ast::*;
-use crate::error::CompilerError;
+use crate::error::{describe, CompilerError, Span};
 use crate::lexing::*;
 use combine::{
-    attempt, between, choice, many, many1, optional, satisfy, sep_by, token, ParseError, Parser,
-    Stream,
-    stream::state::{State, SourcePosition}
+    attempt, between, choice, eof, look_ahead, many, many1, optional, position, satisfy, sep_by, sep_by1, token,
+    ParseError, Parser, Stream,
+    easy,
+    error::StreamError,
+    stream::{state::{Positioner, State}, Resetable, StreamErrorFor}
 };
 
-pub fn parse(tokens: &[Token]) -> Result<Program, CompilerError> {
-    let mut program = many1::<Vec<_>, _>(function()).map(|funs| Program::new(funs));
+pub fn parse(tokens: &[(Token, Span)]) -> Result<Program, CompilerError> {
+    let (tokens, spans): (Vec<_>, Vec<_>) = tokens.iter().cloned().unzip();
+    let mut program = many1::<Vec<_>, _>(choice((
+        struct_declaration().map(|s| vec![TopLevel::Struct(s)]),
+        function().map(|f| vec![TopLevel::Function(f)]),
+        global(),
+    )))
+    .skip(eof())
+    .map(|items: Vec<Vec<_>>| Program::new(items.into_iter().flatten().collect()));
 
-    match program.easy_parse(State::new(tokens)) {
+    let positioner = SpanPositioner {
+        spans: &spans,
+        index: 0,
+    };
+    match program.easy_parse(State::with_positioner(&tokens[..], positioner)) {
         Ok(ast) => Ok(ast.0),
-        Err(e) => Err(CompilerError::Parser(format!("{:?}", e.errors), e.position)),
+        Err(e) => Err(parse_error(e)),
     }
 }
 
+// parses the controlling expression of an #if
+pub fn parse_constant(tokens: &[(Token, Span)]) -> Result<Expression, CompilerError> {
+    let (tokens, spans): (Vec<_>, Vec<_>) = tokens.iter().cloned().unzip();
+    let positioner = SpanPositioner {
+        spans: &spans,
+        index: 0,
+    };
+    let input = State::with_positioner(&tokens[..], positioner);
+    match conditional_exp().skip(eof()).easy_parse(input) {
+        Ok((e, _)) => Ok(e),
+        Err(e) => Err(parse_error(e)),
+    }
+}
+
+// errors are located at the token where parsing stopped
+fn parse_error(e: easy::Errors<Token, &[Token], TokenPosition>) -> CompilerError {
+    // ranges of tokens are shown as they are written
+    let errors: Vec<_> = e
+        .errors
+        .into_iter()
+        .map(|error| error.map_range(|r: &[Token]| {
+            r.iter().map(Token::to_string).collect::<Vec<_>>().join(" ")
+        }))
+        .collect();
+    CompilerError::Parser(describe(&errors), e.position.next.location)
+}
+
+// where the parser stands in the token stream: the span of the next token, from which errors
+// are located, and the end of the previous one, where whatever was parsed up to there ends
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TokenPosition {
+    index: usize,
+    previous_end: usize,
+    next: Span,
+}
+
+impl TokenPosition {
+    // the span of the tokens between two positions
+    fn to(&self, end: &TokenPosition) -> Span {
+        Span {
+            start: self.next.start,
+            end: end.previous_end.max(self.next.start),
+            location: self.next.location.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct SpanPositioner<'a> {
+    spans: &'a [Span],
+    index: usize,
+}
+
+impl<'a> Positioner<Token> for SpanPositioner<'a> {
+    type Position = TokenPosition;
+
+    // the end of input is an empty span right after the last token
+    fn position(&self) -> TokenPosition {
+        let previous = self.index.checked_sub(1).and_then(|i| self.spans.get(i));
+        let previous_end = previous.map_or(0, |span| span.end);
+        let next = match self.spans.get(self.index) {
+            Some(span) => span.clone(),
+            None => Span {
+                start: previous_end,
+                end: previous_end,
+                location: previous.map(|span| span.location.clone()).unwrap_or_default(),
+            },
+        };
+        TokenPosition {
+            index: self.index,
+            previous_end,
+            next,
+        }
+    }
+
+    fn update(&mut self, _: &Token) {
+        self.index += 1;
+    }
+}
+
+impl<'a> Resetable for SpanPositioner<'a> {
+    type Checkpoint = usize;
+
+    fn checkpoint(&self) -> usize {
+        self.index
+    }
+
+    fn reset(&mut self, checkpoint: usize) {
+        self.index = checkpoint;
+    }
+}
+
+// the output of a parser along with the span of the tokens it consumed
+fn spanned<I, P>(parser: P) -> impl Parser<Input = I, Output = (P::Output, Span)>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    P: Parser<Input = I>,
+{
+    (position(), parser, position()).map(|(start, output, end): (TokenPosition, _, TokenPosition)| {
+        (output, start.to(&end))
+    })
+}
+
+// function definitions, prototypes are parsed along with global variables
 fn function<I>() -> impl Parser<Input = I, Output = Function>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    typename()
-        .with(identifier())
-        .and(between(
-            token(Token::OpenParen),
-            token(Token::CloseParen),
-            sep_by::<Vec<_>, _, _>(typename().and(identifier()), token(Token::Comma)), // TODO: support omitting argument names
-        ))
-        .and(choice((
-            between(
+    // the declarator is the same as in prototypes, so the return type may be a pointer to a
+    // function, but it has to end in a parameter list
+    let header = optional(storage_class()).and(type_specifier()).and(any_declarator());
+
+    // only the header may backtrack, errors in the body are reported where they occur
+    spanned(
+        attempt(header.skip(look_ahead(token(Token::OpenBrace))))
+            .and_then(|((storage, t), mut d)| match (d.name, d.derivations.pop()) {
+                (Some(name), Some(Derivation::Function(params, variadic))) => {
+                    let args = params
+                        .into_iter()
+                        .map(|(t, name)| name.map(|name| (t, name)))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| {
+                            StreamErrorFor::<I>::message_static_message(
+                                "expected a parameter name",
+                            )
+                        })?;
+                    let return_type = derive(t, d.derivations).unqualified().clone();
+                    Ok((storage, name, return_type, args, variadic))
+                }
+                _ => Err(StreamErrorFor::<I>::message_static_message(
+                    "expected a function declarator",
+                )),
+            })
+            .and(between(
                 token(Token::OpenBrace),
                 token(Token::CloseBrace),
-                many::<Vec<_>, _>(block_item()),
-            )
-            .map(|args| Some(args)),
-            token(Token::Semicolon).map(|_| None),
-        )))
-        .map(|((name, args), statements)| Function::new(name, args, statements))
+                many::<Vec<Vec<_>>, _>(block_item()),
+            )),
+    )
+    .map(|(((storage, name, return_type, args, variadic), items), span)| {
+        let statements = Some(items.into_iter().flatten().collect());
+        Function::new(name, return_type, args, variadic, storage, statements, span)
+    })
 }
 
-fn block_item<I>() -> impl Parser<Input = I, Output = Statement>
+fn struct_declaration<I>() -> impl Parser<Input = I, Output = Option<StructDef>>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    choice((declaration(), statement()))
+    let fields = type_specifier()
+        .and(sep_by1::<Vec<_>, _, _>(declarator(), token(Token::Comma)))
+        .skip(token(Token::Semicolon))
+        .map(|(t, declarators)| {
+            declarators
+                .into_iter()
+                .map(|d| d.declare(&t))
+                .collect::<Vec<_>>()
+        });
+
+    // struct types may also start the declaration of a variable
+    let name = attempt(
+        token(Token::Struct)
+            .with(identifier())
+            .skip(look_ahead(choice((token(Token::OpenBrace), token(Token::Semicolon))))),
+    );
+
+    spanned(
+        name
+            .and(optional(between(
+                token(Token::OpenBrace),
+                token(Token::CloseBrace),
+                many::<Vec<Vec<_>>, _>(fields),
+            )))
+            .skip(token(Token::Semicolon)),
+    )
+    .map(|((name, fields), span)| {
+        fields.map(|fields| StructDef::new(name, fields.into_iter().flatten().collect(), span))
+    })
 }
 
-fn declaration<I>() -> impl Parser<Input = I, Output = Statement>
+// array and function parameters are really pointers, so the outermost size may be omitted
+// the name is optional, only definitions need it
+fn parameter<I>() -> impl Parser<Input = I, Output = (Type, Option<Identifier>)>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    typename()
-        .and(identifier())
-        .and(optional(token(Token::Assign).with(expression())))
-        .skip(token(Token::Semicolon))
-        .map(|((t, id), expr)| Statement::Declaration(t, id, expr))
+    type_specifier()
+        .and(any_declarator())
+        .map(|(t, d)| (derive(t, d.derivations).decay(), d.name))
+}
+
+// global variables, along with the prototypes of functions declared alongside them
+fn global<I>() -> impl Parser<Input = I, Output = Vec<TopLevel>>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    spanned(
+        optional(storage_class())
+            .and(type_specifier())
+            .and(sep_by1::<Vec<_>, _, _>(
+                init_declarator(),
+                token(Token::Comma),
+            ))
+            .skip(token(Token::Semicolon)),
+    )
+    .map(|(((storage, t), declarators), span)| {
+        declarators
+            .into_iter()
+            .map(|(d, expr)| match (d.declare(&t), expr) {
+                ((Type::Function(return_type, arg_types, variadic), id), None) => {
+                    let args = arg_types.into_iter().map(|t| (t, Identifier::new())).collect();
+                    let (return_type, span) = (*return_type, span.clone());
+                    let f = Function::new(id, return_type, args, variadic, storage, None, span);
+                    TopLevel::Function(f)
+                }
+                ((t, id), expr) => {
+                    TopLevel::Global(Global::new(id, t, expr, storage, span.clone()))
+                }
+            })
+            .collect()
+    })
 }
 
-parser! { fn statement[I]()(I) -> Statement where [I: Stream<Item = Token>] { statement_() }}
+// the part of a declaration specific to one of the variables it declares
+struct Declarator {
+    name: Option<Identifier>, // None for abstract declarators
+    derivations: Vec<Derivation>,
+}
+
+// a type constructor applied to the base type of a declaration
+enum Derivation {
+    Pointer(Qualifiers),
+    Array(Option<Expression>), // None for an omitted size
+    Function(Vec<(Type, Option<Identifier>)>, bool), // parameters and whether variadic
+}
+
+impl Declarator {
+    // type and name of the variable declared with base type t
+    fn declare(self, t: &Type) -> (Type, Identifier) {
+        let name = self.name.expect("declarator should be named");
+        (derive(t.clone(), self.derivations), name)
+    }
+
+    fn is_sized(&self) -> bool {
+        self.derivations
+            .iter()
+            .all(|d| !matches!(d, Derivation::Array(None)))
+    }
+}
+
+// applies the derivations to t, innermost first
+// qualifiers on a returned value are meaningless and dropped
+fn derive(t: Type, derivations: impl IntoIterator<Item = Derivation>) -> Type {
+    derivations.into_iter().fold(t, |prev, d| match d {
+        Derivation::Pointer(qualifiers) => Type::Pointer(Box::new(prev)).qualify(qualifiers),
+        Derivation::Array(len) => {
+            let len = len.map_or(Length::Constant(0), |e| Length::Expression(Box::new(e)));
+            Type::Array(Box::new(prev), len)
+        }
+        Derivation::Function(params, variadic) => {
+            let params = params.into_iter().map(|(t, _)| t.unqualified().clone()).collect();
+            Type::Function(Box::new(prev.unqualified().clone()), params, variadic)
+        }
+    })
+}
+
+// a star and the qualifiers of the pointer it derives, as in `* const`
+fn pointer<I>() -> impl Parser<Input = I, Output = Derivation>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    token(Token::Multiply)
+        .with(qualifiers())
+        .map(Derivation::Pointer)
+}
+
+// a declarator naming a variable of complete array type
+fn declarator<I>() -> impl Parser<Input = I, Output = Declarator>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    any_declarator().and_then(|d| match d.name {
+        Some(_) if d.is_sized() => Ok(d),
+        Some(_) => Err(StreamErrorFor::<I>::message_static_message("array size missing")),
+        None => Err(StreamErrorFor::<I>::message_static_message("expected an identifier")),
+    })
+}
+
+// the name, if any, comes last in the type derivations, so in `*(*x)[3]`
+// x is a pointer to an array of pointers
+parser! {
+    fn any_declarator[I]()(I) -> Declarator
+    where [I: Stream<Item = Token, Position = TokenPosition>]
+    { any_declarator_() }
+}
+fn any_declarator_<I>() -> impl Parser<Input = I, Output = Declarator>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    let nested = attempt(
+        between(
+            token(Token::OpenParen),
+            token(Token::CloseParen),
+            any_declarator(),
+        )
+        .and_then(|d| match (&d.name, d.derivations.is_empty()) {
+            (None, true) => Err(StreamErrorFor::<I>::message_static_message(
+                "expected a declarator",
+            )),
+            _ => Ok(d),
+        }),
+    );
+
+    let suffix = choice((
+        between(
+            token(Token::OpenBracket),
+            token(Token::CloseBracket),
+            optional(conditional_exp()),
+        )
+        .map(Derivation::Array),
+        between(
+            token(Token::OpenParen),
+            token(Token::CloseParen),
+            parameter_list(parameter()),
+        )
+        .map(|(params, variadic)| Derivation::Function(params, variadic)),
+    ));
+
+    many::<Vec<_>, _>(pointer())
+        .and(optional(choice((
+            nested,
+            identifier().map(|name| Declarator {
+                name: Some(name),
+                derivations: Vec::new(),
+            }),
+        ))))
+        .and(many::<Vec<_>, _>(suffix))
+        .map(|((pointers, direct), suffixes)| {
+            let direct = direct.unwrap_or(Declarator {
+                name: None,
+                derivations: Vec::new(),
+            });
+            let mut derivations = pointers;
+            derivations.extend(suffixes.into_iter().rev());
+            derivations.extend(direct.derivations);
+            Declarator {
+                name: direct.name,
+                derivations,
+            }
+        })
+}
+
+// the parameters of a function and whether an ellipsis follows them
+fn parameter_list<I, P>(parameter: P) -> impl Parser<Input = I, Output = (Vec<P::Output>, bool)>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    P: Parser<Input = I>,
+{
+    choice((
+        attempt(token(Token::Void).skip(look_ahead(token(Token::CloseParen))))
+            .map(|_| (Vec::new(), false)),
+        sep_by::<Vec<_>, _, _>(
+            choice((parameter.map(Some), token(Token::Ellipsis).map(|_| None))),
+            token(Token::Comma),
+        )
+        .and_then(|mut params| {
+            // the ellipsis has to follow at least one named parameter
+            let variadic = params.len() > 1 && params.last().is_some_and(Option::is_none);
+            if variadic {
+                params.pop();
+            }
+            params
+                .into_iter()
+                .collect::<Option<Vec<_>>>()
+                .map(|params| (params, variadic))
+                .ok_or_else(|| {
+                    StreamErrorFor::<I>::message_static_message(
+                        "an ellipsis must come last, after a named parameter",
+                    )
+                })
+        }),
+    ))
+}
+
+fn init_declarator<I>() -> impl Parser<Input = I, Output = (Declarator, Option<Expression>)>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    declarator().and(optional(token(Token::Assign).with(assignment_exp())))
+}
+
+fn block_item<I>() -> impl Parser<Input = I, Output = Vec<Statement>>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    choice((declaration(), statement().map(|stm| vec![stm])))
+}
+
+// a declaration may introduce several variables, each getting its own statement
+fn declaration<I>() -> impl Parser<Input = I, Output = Vec<Statement>>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    spanned(
+        optional(storage_class())
+            .and(type_specifier())
+            .and(sep_by1::<Vec<_>, _, _>(
+                init_declarator(),
+                token(Token::Comma),
+            ))
+            .skip(token(Token::Semicolon)),
+    )
+    .map(|(((storage, t), declarators), span)| {
+        declarators
+            .into_iter()
+            .map(|(d, expr)| {
+                let (t, id) = d.declare(&t);
+                Statement::new(StatementKind::Declaration(t, id, expr, storage), span.clone())
+            })
+            .collect()
+    })
+}
+
+fn storage_class<I>() -> impl Parser<Input = I, Output = StorageClass>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    choice((
+        token(Token::Static).map(|_| StorageClass::Static),
+        token(Token::Extern).map(|_| StorageClass::Extern),
+    ))
+}
+
+parser! {
+    fn statement[I]()(I) -> Statement
+    where [I: Stream<Item = Token, Position = TokenPosition>]
+    { statement_() }
+}
 fn statement_<I>() -> impl Parser<Input = I, Output = Statement>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     let return_statement = token(Token::Return)
-        .with(expression())
+        .with(optional(expression()))
         .skip(token(Token::Semicolon))
-        .map(|e| Statement::Return(e));
+        .map(StatementKind::Return);
 
     let expression_statement = optional(expression())
-        .map(|e| Statement::Expression(e))
+        .map(StatementKind::Expression)
         .skip(token(Token::Semicolon));
 
     let if_statement = token(Token::If)
@@ -83,53 +502,50 @@ where
         ))
         .and(statement())
         .and(optional(token(Token::Else).with(statement())))
-        .map(|((cond, stm), alt)| Statement::If(cond, Box::new(stm), alt.map(Box::new)));
+        .map(|((cond, stm), alt)| StatementKind::If(cond, Box::new(stm), alt.map(Box::new)));
 
     let compound_statement = between(
         token(Token::OpenBrace),
         token(Token::CloseBrace),
-        many::<Vec<_>, _>(block_item()),
+        many::<Vec<Vec<_>>, _>(block_item()),
     )
-    .map(|stms| Statement::Compound(stms));
+    .map(|items| StatementKind::Compound(items.into_iter().flatten().collect()));
 
-    let for_statement = token(Token::For)
-        .with(between(
-            token(Token::OpenParen),
-            token(Token::CloseParen),
-            optional(expression())
-                .skip(token(Token::Semicolon))
-                .and(optional(expression()))
-                .skip(token(Token::Semicolon))
-                .and(optional(expression())),
-        ))
-        .and(statement())
-        .map(|(((init, cond), iter), body)| {
-            Statement::For(
-                init,
-                cond.unwrap_or(Expression::Literal(1)),
-                iter,
-                Box::new(body),
-            )
-        });
+    let for_statement = spanned(
+        token(Token::For)
+            .with(between(
+                token(Token::OpenParen),
+                token(Token::CloseParen),
+                optional(expression())
+                    .skip(token(Token::Semicolon))
+                    .and(optional(expression()))
+                    .skip(token(Token::Semicolon))
+                    .and(optional(expression())),
+            ))
+            .and(statement()),
+    )
+    .map(|((((init, cond), iter), body), span)| {
+        // an omitted condition is always true
+        let cond = cond.unwrap_or(Expression::new(ExpressionKind::Literal(1, Type::Int), span));
+        StatementKind::For(init, cond, iter, Box::new(body))
+    });
 
-    let for_decl_statement = token(Token::For)
-        .with(between(
-            token(Token::OpenParen),
-            token(Token::CloseParen),
-            declaration()
-                .and(optional(expression()))
-                .skip(token(Token::Semicolon))
-                .and(optional(expression())),
-        ))
-        .and(statement())
-        .map(|(((init, cond), iter), body)| {
-            Statement::ForDecl(
-                Box::new(init),
-                cond.unwrap_or(Expression::Literal(1)),
-                iter,
-                Box::new(body),
-            )
-        });
+    let for_decl_statement = spanned(
+        token(Token::For)
+            .with(between(
+                token(Token::OpenParen),
+                token(Token::CloseParen),
+                declaration()
+                    .and(optional(expression()))
+                    .skip(token(Token::Semicolon))
+                    .and(optional(expression())),
+            ))
+            .and(statement()),
+    )
+    .map(|((((init, cond), iter), body), span)| {
+        let cond = cond.unwrap_or(Expression::new(ExpressionKind::Literal(1, Type::Int), span));
+        StatementKind::ForDecl(init, cond, iter, Box::new(body))
+    });
 
     let while_statement = token(Token::While)
         .with(between(
@@ -138,7 +554,7 @@ where
             expression(),
         ))
         .and(statement())
-        .map(|(cond, body)| Statement::While(cond, Box::new(body)));
+        .map(|(cond, body)| StatementKind::While(cond, Box::new(body)));
 
     let do_statement = token(Token::Do)
         .with(statement())
@@ -149,17 +565,47 @@ where
             expression(),
         ))
         .skip(token(Token::Semicolon))
-        .map(|(body, cond)| Statement::Do(Box::new(body), cond));
+        .map(|(body, cond)| StatementKind::Do(Box::new(body), cond));
+
+    let switch_statement = token(Token::Switch)
+        .with(between(
+            token(Token::OpenParen),
+            token(Token::CloseParen),
+            expression(),
+        ))
+        .and(statement())
+        .map(|(cond, body)| StatementKind::Switch(cond, Box::new(body)));
+
+    let case_statement = token(Token::Case)
+        .with(conditional_exp())
+        .skip(token(Token::Colon))
+        .and(statement())
+        .map(|(value, stm)| StatementKind::Case(value, Box::new(stm)));
+
+    let default_statement = token(Token::Default)
+        .skip(token(Token::Colon))
+        .with(statement())
+        .map(|stm| StatementKind::Default(Box::new(stm)));
+
+    let labeled_statement = identifier()
+        .skip(token(Token::Colon))
+        .and(statement())
+        .map(|(label, stm)| StatementKind::Labeled(label, Box::new(stm)));
+
+    let goto_statement = token(Token::Goto)
+        .with(identifier())
+        .skip(token(Token::Semicolon))
+        .map(StatementKind::Goto);
 
     let break_statement = token(Token::Break)
         .skip(token(Token::Semicolon))
-        .map(|_| Statement::Break);
+        .map(|_| StatementKind::Break);
 
     let continue_statement = token(Token::Continue)
         .skip(token(Token::Semicolon))
-        .map(|_| Statement::Continue);
+        .map(|_| StatementKind::Continue);
 
-    choice((
+    spanned(choice((
         compound_statement,
         return_statement,
         if_statement,
@@ -167,83 +613,220 @@ where
         for_statement,
         while_statement,
         do_statement,
+        switch_statement,
+        case_statement,
+        default_statement,
+        goto_statement,
+        attempt(labeled_statement),
         expression_statement,
         break_statement,
         continue_statement,
-    ))
+    )))
+    .map(|(kind, span)| Statement::new(kind, span))
 }
 
-fn factor<I>() -> impl Parser<Input = I, Output = Expression>
+parser! {
+    fn factor[I]()(I) -> Expression
+    where [I: Stream<Item = Token, Position = TokenPosition>]
+    { factor_() }
+}
+fn factor_<I>() -> impl Parser<Input = I, Output = Expression>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     let unary_op = choice((
         token(Token::Minus),
         token(Token::BinaryNot),
         token(Token::LogicalNot),
+        token(Token::Multiply),
+        token(Token::BitwiseAnd),
+        token(Token::Increment),
+        token(Token::Decrement),
     ))
-    .and(expression())
+    .and(factor())
     .map(|(op, e)| match op {
-        Token::Minus => Expression::Minus(Box::new(e)),
-        Token::BinaryNot => Expression::BinaryNot(Box::new(e)),
-        Token::LogicalNot => Expression::LogicalNot(Box::new(e)),
+        Token::Minus => ExpressionKind::Minus(Box::new(e)),
+        Token::BinaryNot => ExpressionKind::BinaryNot(Box::new(e)),
+        Token::LogicalNot => ExpressionKind::LogicalNot(Box::new(e)),
+        Token::Multiply => ExpressionKind::Deref(Box::new(e)),
+        Token::BitwiseAnd => ExpressionKind::AddressOf(Box::new(e)),
+        Token::Increment => ExpressionKind::PreIncrement(Box::new(e)),
+        Token::Decrement => ExpressionKind::PreDecrement(Box::new(e)),
         _ => unreachable!(),
     });
 
-    let unary_lvalue_pre = choice((token(Token::Increment), token(Token::Decrement)))
-        .and(identifier())
-        .map(|(op, id)| match op {
-            Token::Increment => Expression::PreIncrement(id),
-            Token::Decrement => Expression::PreDecrement(id),
-            _ => unreachable!(),
-        });
+    // the operand of sizeof is either a parenthesized type name or an expression
+    let sizeof = token(Token::Sizeof).with(choice((
+        attempt(between(
+            token(Token::OpenParen),
+            token(Token::CloseParen),
+            typename(),
+        ))
+        .map(ExpressionKind::SizeOf),
+        factor().map(|e| ExpressionKind::SizeOfExpr(Box::new(e))),
+    )));
 
-    let unary_lvalue_post = identifier()
-        .and(choice((token(Token::Increment), token(Token::Decrement))))
-        .map(|(id, op)| match op {
-            Token::Increment => Expression::PostIncrement(id),
-            Token::Decrement => Expression::PostDecrement(id),
-            _ => unreachable!(),
-        });
-    let function_call = identifier()
-        .and(between(
+    let alignof = token(Token::Alignof)
+        .with(between(
             token(Token::OpenParen),
             token(Token::CloseParen),
-            sep_by::<Vec<_>, _, _>(expression(), token(Token::Comma)),
+            typename(),
         ))
-        .map(|(id, args)| Expression::FunCall(id, args));
+        .map(ExpressionKind::AlignOf);
+
+    // va_end has nothing to release, va_copy copies the whole structure
+    let va_builtin = choice((
+        token(Token::VaStart)
+            .with(between(
+                token(Token::OpenParen),
+                token(Token::CloseParen),
+                assignment_exp().skip(optional(token(Token::Comma).with(identifier()))),
+            ))
+            .map(|ap| ExpressionKind::VaStart(Box::new(ap))),
+        token(Token::VaArg)
+            .with(between(
+                token(Token::OpenParen),
+                token(Token::CloseParen),
+                assignment_exp().skip(token(Token::Comma)).and(typename()),
+            ))
+            .map(|(ap, t)| ExpressionKind::VaArg(Box::new(ap), t)),
+        token(Token::VaEnd)
+            .with(between(
+                token(Token::OpenParen),
+                token(Token::CloseParen),
+                assignment_exp(),
+            ))
+            .map(|ap| ExpressionKind::Cast(Type::Void, Box::new(ap))),
+        token(Token::VaCopy)
+            .with(between(
+                token(Token::OpenParen),
+                token(Token::CloseParen),
+                assignment_exp().skip(token(Token::Comma)).and(assignment_exp()),
+            ))
+            .map(|(dest, src)| {
+                let (dest_span, src_span) = (dest.span.clone(), src.span.clone());
+                ExpressionKind::Assignment(
+                    Box::new(Expression::new(ExpressionKind::Deref(Box::new(dest)), dest_span)),
+                    Box::new(Expression::new(ExpressionKind::Deref(Box::new(src)), src_span)),
+                )
+            }),
+    ));
+
+    let cast = attempt(between(
+        token(Token::OpenParen),
+        token(Token::CloseParen),
+        typename(),
+    ))
+    .and(factor())
+    .map(|(t, e)| ExpressionKind::Cast(t, Box::new(e)));
+
+    choice((
+        spanned(choice((unary_op, sizeof, alignof, va_builtin, cast)))
+            .map(|(kind, span)| Expression::new(kind, span)),
+        postfix_exp(),
+    ))
+}
 
+enum Postfix {
+    Increment,
+    Decrement,
+    Index(Expression),
+    Call(Vec<Expression>),
+    Member(Identifier),
+    PointerMember(Identifier),
+}
+
+fn postfix_exp<I>() -> impl Parser<Input = I, Output = Expression>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    primary_exp()
+        .and(many::<Vec<_>, _>(spanned(choice((
+            token(Token::Increment).map(|_| Postfix::Increment),
+            token(Token::Decrement).map(|_| Postfix::Decrement),
+            token(Token::Dot)
+                .with(identifier())
+                .map(Postfix::Member),
+            token(Token::Arrow)
+                .with(identifier())
+                .map(Postfix::PointerMember),
+            between(
+                token(Token::OpenBracket),
+                token(Token::CloseBracket),
+                expression(),
+            )
+            .map(Postfix::Index),
+            between(
+                token(Token::OpenParen),
+                token(Token::CloseParen),
+                sep_by(assignment_exp(), token(Token::Comma)),
+            )
+            .map(Postfix::Call),
+        )))))
+        .map(|(first, remainder)| {
+            remainder.into_iter().fold(first, |prev, (op, op_span)| {
+                let span = prev.span.to(&op_span);
+                let kind = match op {
+                    Postfix::Increment => ExpressionKind::PostIncrement(Box::new(prev)),
+                    Postfix::Decrement => ExpressionKind::PostDecrement(Box::new(prev)),
+                    Postfix::Call(args) => ExpressionKind::FunCall(Box::new(prev), args),
+                    Postfix::Member(id) => ExpressionKind::Member(Box::new(prev), id),
+                    // a->b is (*a).b
+                    Postfix::PointerMember(id) => {
+                        let pointee = ExpressionKind::Deref(Box::new(prev));
+                        ExpressionKind::Member(Box::new(Expression::new(pointee, span.clone())), id)
+                    }
+                    // a[i] is *(a + i)
+                    Postfix::Index(i) => {
+                        let address = Expression::binary(ExpressionKind::Add, prev, i);
+                        ExpressionKind::Deref(Box::new(address))
+                    }
+                };
+                Expression::new(kind, span)
+            })
+        })
+}
+
+fn primary_exp<I>() -> impl Parser<Input = I, Output = Expression>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
     choice((
-        attempt(unary_lvalue_pre),
-        attempt(unary_lvalue_post),
-        unary_op,
-        attempt(function_call),
         literal(),
         between(
             token(Token::OpenParen),
             token(Token::CloseParen),
             expression(),
         ),
-        identifier().map(|id| Expression::Identifier(id)),
+        spanned(identifier())
+            .map(|(id, span)| Expression::new(ExpressionKind::Identifier(id), span)),
     ))
 }
 
 fn term<I>() -> impl Parser<Input = I, Output = Expression>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     factor()
         .and(many::<Vec<_>, _>(
-            choice((token(Token::Multiply), token(Token::Divide))).and(factor()),
+            choice((
+                token(Token::Multiply),
+                token(Token::Divide),
+                token(Token::Modulo),
+            ))
+            .and(factor()),
         ))
         .map(|(first, remainder)| {
             remainder
                 .into_iter()
                 .fold(first, |prev, (op, next)| match op {
-                    Token::Multiply => Expression::Multiply(Box::new(prev), Box::new(next)),
-                    Token::Divide => Expression::Divide(Box::new(prev), Box::new(next)),
+                    Token::Multiply => Expression::binary(ExpressionKind::Multiply, prev, next),
+                    Token::Divide => Expression::binary(ExpressionKind::Divide, prev, next),
+                    Token::Modulo => Expression::binary(ExpressionKind::Modulo, prev, next),
                     _ => unreachable!(),
                 })
         })
@@ -251,7 +834,7 @@ where
 
 fn additive_exp<I>() -> impl Parser<Input = I, Output = Expression>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     term()
@@ -262,19 +845,46 @@ where
             remainder
                 .into_iter()
                 .fold(first, |prev, (op, next)| match op {
-                    Token::Add => Expression::Add(Box::new(prev), Box::new(next)),
-                    Token::Minus => Expression::Subtract(Box::new(prev), Box::new(next)),
+                    Token::Add => Expression::binary(ExpressionKind::Add, prev, next),
+                    Token::Minus => Expression::binary(ExpressionKind::Subtract, prev, next),
                     _ => unreachable!(),
                 })
         })
 }
 
-fn relational_exp<I>() -> impl Parser<Input = I, Output = Expression>
+// each precedence level doubles combine's parser count, declaring some levels
+// through parser! keeps it from overflowing
+parser! {
+    fn shift_exp[I]()(I) -> Expression
+    where [I: Stream<Item = Token, Position = TokenPosition>]
+    { shift_exp_() }
+}
+fn shift_exp_<I>() -> impl Parser<Input = I, Output = Expression>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     additive_exp()
+        .and(many::<Vec<_>, _>(
+            choice((token(Token::ShiftLeft), token(Token::ShiftRight))).and(additive_exp()),
+        ))
+        .map(|(first, remainder)| {
+            remainder
+                .into_iter()
+                .fold(first, |prev, (op, next)| match op {
+                    Token::ShiftLeft => Expression::binary(ExpressionKind::ShiftLeft, prev, next),
+                    Token::ShiftRight => Expression::binary(ExpressionKind::ShiftRight, prev, next),
+                    _ => unreachable!(),
+                })
+        })
+}
+
+fn relational_exp<I>() -> impl Parser<Input = I, Output = Expression>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    shift_exp()
         .and(many::<Vec<_>, _>(
             choice((
                 token(Token::LessThan),
@@ -282,19 +892,21 @@ where
                 token(Token::GreaterThan),
                 token(Token::GreaterThanOrEqual),
             ))
-            .and(additive_exp()),
+            .and(shift_exp()),
         ))
         .map(|(first, remainder)| {
             remainder
                 .into_iter()
                 .fold(first, |prev, (op, next)| match op {
-                    Token::LessThan => Expression::LessThan(Box::new(prev), Box::new(next)),
+                    Token::LessThan => Expression::binary(ExpressionKind::LessThan, prev, next),
                     Token::LessThanOrEqual => {
-                        Expression::LessThanOrEqual(Box::new(prev), Box::new(next))
+                        Expression::binary(ExpressionKind::LessThanOrEqual, prev, next)
+                    }
+                    Token::GreaterThan => {
+                        Expression::binary(ExpressionKind::GreaterThan, prev, next)
                     }
-                    Token::GreaterThan => Expression::GreaterThan(Box::new(prev), Box::new(next)),
                     Token::GreaterThanOrEqual => {
-                        Expression::GreaterThanOrEqual(Box::new(prev), Box::new(next))
+                        Expression::binary(ExpressionKind::GreaterThanOrEqual, prev, next)
                     }
                     _ => unreachable!(),
                 })
@@ -303,7 +915,7 @@ where
 
 fn equality_exp<I>() -> impl Parser<Input = I, Output = Expression>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     relational_exp()
@@ -314,25 +926,72 @@ where
             remainder
                 .into_iter()
                 .fold(first, |prev, (op, next)| match op {
-                    Token::Equal => Expression::Equal(Box::new(prev), Box::new(next)),
-                    Token::NotEqual => Expression::NotEqual(Box::new(prev), Box::new(next)),
+                    Token::Equal => Expression::binary(ExpressionKind::Equal, prev, next),
+                    Token::NotEqual => Expression::binary(ExpressionKind::NotEqual, prev, next),
                     _ => unreachable!(),
                 })
         })
 }
 
-fn logical_and_exp<I>() -> impl Parser<Input = I, Output = Expression>
+fn bitwise_and_exp<I>() -> impl Parser<Input = I, Output = Expression>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     equality_exp()
-        .and(many::<Vec<_>, _>(token(Token::And).and(equality_exp())))
+        .and(many::<Vec<_>, _>(token(Token::BitwiseAnd).with(equality_exp())))
+        .map(|(first, remainder)| {
+            remainder.into_iter().fold(first, |prev, next| {
+                Expression::binary(ExpressionKind::BitwiseAnd, prev, next)
+            })
+        })
+}
+
+fn bitwise_xor_exp<I>() -> impl Parser<Input = I, Output = Expression>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    bitwise_and_exp()
+        .and(many::<Vec<_>, _>(token(Token::BitwiseXor).with(bitwise_and_exp())))
+        .map(|(first, remainder)| {
+            remainder.into_iter().fold(first, |prev, next| {
+                Expression::binary(ExpressionKind::BitwiseXor, prev, next)
+            })
+        })
+}
+
+parser! {
+    fn bitwise_or_exp[I]()(I) -> Expression
+    where [I: Stream<Item = Token, Position = TokenPosition>]
+    { bitwise_or_exp_() }
+}
+fn bitwise_or_exp_<I>() -> impl Parser<Input = I, Output = Expression>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    bitwise_xor_exp()
+        .and(many::<Vec<_>, _>(token(Token::BitwiseOr).with(bitwise_xor_exp())))
+        .map(|(first, remainder)| {
+            remainder.into_iter().fold(first, |prev, next| {
+                Expression::binary(ExpressionKind::BitwiseOr, prev, next)
+            })
+        })
+}
+
+fn logical_and_exp<I>() -> impl Parser<Input = I, Output = Expression>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    bitwise_or_exp()
+        .and(many::<Vec<_>, _>(token(Token::And).and(bitwise_or_exp())))
         .map(|(first, remainder)| {
             remainder
                 .into_iter()
                 .fold(first, |prev, (op, next)| match op {
-                    Token::And => Expression::And(Box::new(prev), Box::new(next)),
+                    Token::And => Expression::binary(ExpressionKind::And, prev, next),
                     _ => unreachable!(),
                 })
         })
@@ -340,7 +999,7 @@ where
 
 fn logical_or_exp<I>() -> impl Parser<Input = I, Output = Expression>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     logical_and_exp()
@@ -349,16 +1008,20 @@ where
             remainder
                 .into_iter()
                 .fold(first, |prev, (op, next)| match op {
-                    Token::Or => Expression::Or(Box::new(prev), Box::new(next)),
+                    Token::Or => Expression::binary(ExpressionKind::Or, prev, next),
                     _ => unreachable!(),
                 })
         })
 }
 
-parser! { fn conditional_exp[I]()(I) -> Expression where [I: Stream<Item = Token>] { conditional_exp_() }}
+parser! {
+    fn conditional_exp[I]()(I) -> Expression
+    where [I: Stream<Item = Token, Position = TokenPosition>]
+    { conditional_exp_() }
+}
 fn conditional_exp_<I>() -> impl Parser<Input = I, Output = Expression>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     logical_or_exp()
@@ -371,106 +1034,355 @@ where
         .map(|(cond, remainder)| match remainder {
             None => cond,
             Some((exp, alt)) => {
-                Expression::Conditional(Box::new(cond), Box::new(exp), Box::new(alt))
+                let span = cond.span.to(&alt.span);
+                let (exp, alt) = (Box::new(exp), Box::new(alt));
+                Expression::new(ExpressionKind::Conditional(Box::new(cond), exp, alt), span)
             }
         })
 }
 
-parser! { fn expression[I]()(I) -> Expression where [I: Stream<Item = Token>] { expression_() }}
+parser! {
+    fn expression[I]()(I) -> Expression
+    where [I: Stream<Item = Token, Position = TokenPosition>]
+    { expression_() }
+}
 fn expression_<I>() -> impl Parser<Input = I, Output = Expression>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    choice((
-        attempt(
-            identifier()
-                .and(choice((
-                    token(Token::Assign),
-                    token(Token::AssignAdd),
-                    token(Token::AssignMinus),
-                    token(Token::AssignMultiply),
-                    token(Token::AssignDivide),
-                )))
-                .and(expression())
-                .map(|((id, op), expr)| match op {
-                    Token::Assign => Expression::Assignment(id, Box::new(expr)),
-                    Token::AssignAdd => Expression::Assignment(
-                        id.clone(),
-                        Box::new(Expression::Add(
-                            Box::new(Expression::Identifier(id)),
-                            Box::new(expr),
-                        )),
-                    ),
-                    Token::AssignMinus => Expression::Assignment(
-                        id.clone(),
-                        Box::new(Expression::Subtract(
-                            Box::new(Expression::Identifier(id)),
-                            Box::new(expr),
-                        )),
-                    ),
-                    Token::AssignMultiply => Expression::Assignment(
-                        id.clone(),
-                        Box::new(Expression::Multiply(
-                            Box::new(Expression::Identifier(id)),
-                            Box::new(expr),
-                        )),
-                    ),
-                    Token::AssignDivide => Expression::Assignment(
-                        id.clone(),
-                        Box::new(Expression::Divide(
-                            Box::new(Expression::Identifier(id)),
-                            Box::new(expr),
-                        )),
-                    ),
+    assignment_exp()
+        .and(many::<Vec<_>, _>(token(Token::Comma).with(assignment_exp())))
+        .map(|(first, remainder)| {
+            remainder
+                .into_iter()
+                .fold(first, |prev, next| Expression::binary(ExpressionKind::Comma, prev, next))
+        })
+}
+
+parser! {
+    fn assignment_exp[I]()(I) -> Expression
+    where [I: Stream<Item = Token, Position = TokenPosition>]
+    { assignment_exp_() }
+}
+fn assignment_exp_<I>() -> impl Parser<Input = I, Output = Expression>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    conditional_exp()
+        .and(optional(
+            choice((
+                token(Token::Assign),
+                token(Token::AssignAdd),
+                token(Token::AssignMinus),
+                token(Token::AssignMultiply),
+                token(Token::AssignDivide),
+                token(Token::AssignModulo),
+                token(Token::AssignBitwiseAnd),
+                token(Token::AssignBitwiseOr),
+                token(Token::AssignBitwiseXor),
+                token(Token::AssignShiftLeft),
+                token(Token::AssignShiftRight),
+            ))
+            .and(assignment_exp()),
+        ))
+        .map(|(lhs, assignment)| match assignment {
+            None => lhs,
+            Some((Token::Assign, rhs)) => Expression::binary(ExpressionKind::Assignment, lhs, rhs),
+            Some((op, rhs)) => {
+                let op = match op {
+                    Token::AssignAdd => CompoundOperator::Add,
+                    Token::AssignMinus => CompoundOperator::Subtract,
+                    Token::AssignMultiply => CompoundOperator::Multiply,
+                    Token::AssignDivide => CompoundOperator::Divide,
+                    Token::AssignModulo => CompoundOperator::Modulo,
+                    Token::AssignBitwiseAnd => CompoundOperator::BitwiseAnd,
+                    Token::AssignBitwiseOr => CompoundOperator::BitwiseOr,
+                    Token::AssignBitwiseXor => CompoundOperator::BitwiseXor,
+                    Token::AssignShiftLeft => CompoundOperator::ShiftLeft,
+                    Token::AssignShiftRight => CompoundOperator::ShiftRight,
                     _ => unreachable!(),
-                }),
-        ),
-        conditional_exp(),
-    ))
+                };
+                let span = lhs.span.to(&rhs.span);
+                let kind = ExpressionKind::CompoundAssignment(op, Box::new(lhs), Box::new(rhs));
+                Expression::new(kind, span)
+            }
+        })
 }
 
 fn literal<I>() -> impl Parser<Input = I, Output = Expression>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    satisfy(|t| match t {
-        Token::Integer(_) => true,
-        _ => false,
-    })
-    .map(|t| match t {
-        Token::Integer(i) => Expression::Literal(i),
+    let string_literal = satisfy(|t| matches!(t, Token::StringLiteral(_))).map(|t| match t {
+        Token::StringLiteral(s) => s,
         _ => unreachable!(),
-    })
+    });
+
+    spanned(choice((
+        satisfy(|t| matches!(t, Token::FloatLiteral(_) | Token::DoubleLiteral(_))).map(|t| match t {
+            Token::FloatLiteral(f) => ExpressionKind::FloatLiteral(f as f64, Type::Float),
+            Token::DoubleLiteral(f) => ExpressionKind::FloatLiteral(f, Type::Double),
+            _ => unreachable!(),
+        }),
+        satisfy(|t| matches!(t, Token::Integer(_, _) | Token::CharLiteral(_))).map(|t| match t {
+            Token::Integer(i, format) => ExpressionKind::Literal(i, integer_type(i, format)),
+            // char is signed, so characters past 127 make negative constants
+            Token::CharLiteral(c) => ExpressionKind::Literal(c as i8 as usize, Type::Int),
+            _ => unreachable!(),
+        }),
+        // adjacent string literals are concatenated
+        many1::<Vec<_>, _>(string_literal).map(|s| ExpressionKind::StringLiteral(s.concat())),
+    )))
+    .map(|(kind, span)| Expression::new(kind, span))
+}
+
+// the first type able to represent the value among those allowed by the way it is written:
+// only suffixed or non decimal literals may become unsigned
+fn integer_type(value: usize, format: IntegerFormat) -> Type {
+    let fits = |t: &Type| match t {
+        Type::Int => value <= i32::MAX as usize,
+        Type::UInt => value <= u32::MAX as usize,
+        Type::Long | Type::LongLong => value <= i64::MAX as usize,
+        _ => true,
+    };
+    vec![Type::Int, Type::Long, Type::LongLong]
+        .into_iter()
+        .skip(format.long)
+        .flat_map(|t| {
+            let unsigned = t.to_unsigned();
+            match (format.unsigned, format.decimal) {
+                (true, _) => vec![unsigned],
+                (false, true) => vec![t],
+                (false, false) => vec![t, unsigned],
+            }
+        })
+        .find(fits)
+        // decimal literals too large for long long are taken as unsigned, as GCC does
+        .unwrap_or(Type::ULongLong)
 }
 
 fn identifier<I>() -> impl Parser<Input = I, Output = Identifier>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    satisfy(|t| match t {
-        Token::Identifier(_) => true,
-        _ => false,
-    })
+    satisfy(|t| matches!(t, Token::Identifier(_)))
     .map(|t| match t {
         Token::Identifier(i) => i,
         _ => unreachable!(),
     })
 }
 
+fn type_specifier<I>() -> impl Parser<Input = I, Output = Type>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    let specifier = satisfy(|t| {
+        matches!(
+            t,
+            Token::Void
+                | Token::Float
+                | Token::Double
+                | Token::Char
+                | Token::Short
+                | Token::Int
+                | Token::Long
+                | Token::Signed
+                | Token::Unsigned
+        )
+    });
+
+    // qualifiers may be mixed with basic specifiers or surround any other one
+    let basic = many1::<Vec<_>, _>(choice((
+        specifier.map(|s| (Some(s), Qualifiers::default())),
+        qualifier().map(|q| (None, q)),
+    )))
+    .and_then(|items| {
+        let (specifiers, qualifiers): (Vec<_>, Vec<_>) = items.into_iter().unzip();
+        let specifiers: Vec<_> = specifiers.into_iter().flatten().collect();
+        let qualifiers = qualifiers.into_iter().fold(Qualifiers::default(), Qualifiers::union);
+        base_type(&specifiers)
+            .map(|t| t.qualify(qualifiers))
+            .ok_or_else(|| {
+                StreamErrorFor::<I>::message_static_message(
+                    "invalid combination of type specifiers",
+                )
+            })
+    });
+
+    qualifiers()
+        .and(choice((
+            basic,
+            token(Token::Struct).with(identifier()).map(Type::Struct),
+            token(Token::VaList).map(|_| Type::va_list()),
+        )))
+        .and(qualifiers())
+        .map(|((before, t), after)| t.qualify(before.union(after)))
+}
+
+fn qualifier<I>() -> impl Parser<Input = I, Output = Qualifiers>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    choice((
+        token(Token::Const).map(|_| Qualifiers {
+            constant: true,
+            volatile: false,
+        }),
+        token(Token::Volatile).map(|_| Qualifiers {
+            constant: false,
+            volatile: true,
+        }),
+    ))
+}
+
+// repeating a qualifier has no effect
+fn qualifiers<I>() -> impl Parser<Input = I, Output = Qualifiers>
+where
+    I: Stream<Item = Token, Position = TokenPosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    many::<Vec<_>, _>(qualifier())
+        .map(|qualifiers| qualifiers.into_iter().fold(Qualifiers::default(), Qualifiers::union))
+}
+
 fn typename<I>() -> impl Parser<Input = I, Output = Type>
 where
-    I: Stream<Item = Token>,
+    I: Stream<Item = Token, Position = TokenPosition>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    satisfy(|t| match t {
-        Token::Int => true,
-        _ => false,
-    })
-    .map(|t| match t {
-        Token::Int => Type::Int,
-        _ => unreachable!(),
-    })
+    type_specifier()
+        .and(any_declarator().and_then(|d| match d.name {
+            None => Ok(d.derivations),
+            Some(_) => Err(StreamErrorFor::<I>::message_static_message(
+                "unexpected identifier in type name",
+            )),
+        }))
+        .map(|(t, derivations)| derive(t, derivations))
+}
+
+// the type named by a list of basic type specifiers, which may come in any order
+fn base_type(specifiers: &[Token]) -> Option<Type> {
+    let count = |t: Token| specifiers.iter().filter(|&s| *s == t).count();
+    let (signed, unsigned) = (count(Token::Signed), count(Token::Unsigned));
+    if signed + unsigned > 1 {
+        return None;
+    }
+
+    if specifiers == [Token::Float] {
+        return Some(Type::Float);
+    } else if specifiers == [Token::Double] {
+        return Some(Type::Double);
+    }
+
+    let t = match (
+        count(Token::Void),
+        count(Token::Char),
+        count(Token::Short),
+        count(Token::Int),
+        count(Token::Long),
+    ) {
+        (1, 0, 0, 0, 0) if signed + unsigned == 0 => return Some(Type::Void),
+        (0, 1, 0, 0, 0) if signed == 1 => return Some(Type::SChar),
+        (0, 1, 0, 0, 0) => Type::Char,
+        (0, 0, 1, 0..=1, 0) => Type::Short,
+        (0, 0, 0, 0..=1, 0) => Type::Int,
+        (0, 0, 0, 0..=1, 1) => Type::Long,
+        (0, 0, 0, 0..=1, 2) => Type::LongLong,
+        _ => return None,
+    };
+    Some(if unsigned == 1 { t.to_unsigned() } else { t })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn literal_type(text: &str) -> Type {
+        match parse_constant(&lex(text).unwrap()).unwrap().kind {
+            ExpressionKind::Literal(_, t) => t,
+            e => panic!("{} is not a literal: {:?}", text, e),
+        }
+    }
+
+    #[test]
+    fn suffixes_choose_the_type() {
+        assert_eq!(literal_type("1"), Type::Int);
+        assert_eq!(literal_type("1u"), Type::UInt);
+        assert_eq!(literal_type("1l"), Type::Long);
+        assert_eq!(literal_type("1ul"), Type::ULong);
+        assert_eq!(literal_type("1ll"), Type::LongLong);
+        assert_eq!(literal_type("1llu"), Type::ULongLong);
+    }
+
+    #[test]
+    fn large_literals_are_promoted() {
+        // decimal literals stay signed, others may become unsigned before widening
+        assert_eq!(literal_type("2147483647"), Type::Int);
+        assert_eq!(literal_type("2147483648"), Type::Long);
+        assert_eq!(literal_type("0x7fffffff"), Type::Int);
+        assert_eq!(literal_type("0x80000000"), Type::UInt);
+        assert_eq!(literal_type("020000000000"), Type::UInt);
+        assert_eq!(literal_type("0x100000000"), Type::Long);
+        assert_eq!(literal_type("0x8000000000000000"), Type::ULong);
+        assert_eq!(literal_type("4294967296u"), Type::ULong);
+        assert_eq!(literal_type("0x80000000l"), Type::Long);
+        assert_eq!(literal_type("9223372036854775808"), Type::ULongLong);
+    }
+
+    fn program(text: &str) -> Result<Program, CompilerError> {
+        parse(&lex(text).unwrap())
+    }
+
+    fn function(ret: Type, params: Vec<Type>) -> Type {
+        Type::Function(Box::new(ret), params, false)
+    }
+
+    fn pointer(t: Type) -> Type {
+        Type::Pointer(Box::new(t))
+    }
+
+    #[test]
+    fn function_pointer_declarators() {
+        let binary = function(Type::Int, vec![Type::Int, Type::Int]);
+        let globals = program("int (*op)(int, int); void (*handlers[4])(void);").unwrap().globals;
+        assert_eq!(globals[0].typename, pointer(binary));
+        // array lengths are only computed when lowering
+        match &globals[1].typename {
+            Type::Array(element, _) => assert_eq!(**element, pointer(function(Type::Void, vec![]))),
+            t => panic!("expected an array, got {:?}", t),
+        }
+    }
+
+    #[test]
+    fn functions_returning_function_pointers() {
+        let binary = function(Type::Int, vec![Type::Int, Type::Int]);
+        let funs = program("int (*pick(int i))(int, int) { return 0; }").unwrap().funs;
+        assert_eq!(funs[0].name, "pick");
+        assert_eq!(funs[0].return_type, pointer(binary));
+        assert_eq!(funs[0].args, [(Type::Int, "i".to_string())]);
+    }
+
+    #[test]
+    fn function_parameters_are_pointers() {
+        let funs = program("int apply(int f(int), int x) { return f(x); }").unwrap().funs;
+        let f = pointer(function(Type::Int, vec![Type::Int]));
+        assert_eq!(funs[0].args, [(f, "f".to_string()), (Type::Int, "x".to_string())]);
+    }
+
+    #[test]
+    fn only_prototypes_may_omit_parameter_names() {
+        assert!(program("int f(int, char *);").is_ok());
+        match program("int f(int) { return 0; }") {
+            Err(CompilerError::Parser(message, _)) => {
+                assert_eq!(message, "expected a parameter name")
+            }
+            r => panic!("expected a parser error, got {:?}", r),
+        }
+    }
 }
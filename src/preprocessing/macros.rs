@@ -0,0 +1,391 @@
+use super::tokens::{tokenize, Kind, PpToken};
+use crate::error::{CompilerError, Location};
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Debug)]
+struct Macro {
+    params: Option<Vec<String>>, // None for object-like macros
+    body: Vec<PpToken>,
+}
+
+impl Macro {
+    // redefinitions must be identical, whitespace aside from its presence
+    fn same_as(&self, other: &Macro) -> bool {
+        self.params == other.params
+            && self.body.len() == other.body.len()
+            && self.body.iter().zip(other.body.iter()).enumerate().all(|(i, (t1, t2))| {
+                t1.text == t2.text && (i == 0 || t1.space == t2.space)
+            })
+    }
+
+    fn param(&self, token: &PpToken) -> Option<usize> {
+        let name = token.identifier()?;
+        self.params.as_ref()?.iter().position(|p| p == name)
+    }
+}
+
+// the macros defined at some point of the preprocessing
+#[derive(Debug, Default)]
+pub struct Macros(HashMap<String, Macro>);
+
+impl Macros {
+    // defines the macro described by the tokens following #define
+    pub fn define(&mut self, tokens: &[PpToken], location: &Location) -> Result<(), CompilerError> {
+        let name = match tokens.first().and_then(PpToken::identifier) {
+            Some("defined") => {
+                return Err(error("defined cannot be used as a macro name", location));
+            }
+            Some(name) => name.to_string(),
+            None => return Err(error("Macro names must be identifiers", location)),
+        };
+
+        // a parenthesis right after the name makes a function-like macro
+        let (params, body) = match tokens.get(1) {
+            Some(paren) if paren.is("(") && !paren.space => {
+                let close = tokens
+                    .iter()
+                    .position(|t| t.is(")"))
+                    .ok_or_else(|| error("Missing ) in macro parameter list", location))?;
+                let mut params = Vec::new();
+                for (i, t) in tokens[2..close].iter().enumerate() {
+                    match (i % 2, t.identifier()) {
+                        (0, Some(param)) if params.iter().any(|p| p == param) => {
+                            let message = format!("Duplicate macro parameter {}", param);
+                            return Err(error(message, location));
+                        }
+                        (0, Some(param)) => params.push(param.to_string()),
+                        (1, _) if t.is(",") && close > i + 3 => {}
+                        _ => return Err(error("Expected a macro parameter name", location)),
+                    }
+                }
+                (Some(params), &tokens[close + 1..])
+            }
+            _ => (None, &tokens[1..]),
+        };
+
+        let definition = Macro {
+            params,
+            body: body.to_vec(),
+        };
+        if body.first().is_some_and(|t| t.is("##")) || body.last().is_some_and(|t| t.is("##")) {
+            return Err(error("## cannot appear at either end of a macro expansion", location));
+        }
+        if definition.params.is_some() {
+            let stringized = body.iter().zip(body.iter().skip(1).map(Some).chain([None]));
+            for (t, next) in stringized {
+                if t.is("#") && next.and_then(|n| definition.param(n)).is_none() {
+                    return Err(error("# is not followed by a macro parameter", location));
+                }
+            }
+        }
+        if let Some(previous) = self.0.get(&name) {
+            if !previous.same_as(&definition) {
+                return Err(error(format!("Macro {} redefined", name), location));
+            }
+        }
+        self.0.insert(name, definition);
+        Ok(())
+    }
+
+    pub fn undefine(&mut self, name: &str) {
+        self.0.remove(name);
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.0.contains_key(name) || name == "__LINE__" || name == "__FILE__"
+    }
+
+    // replaces every macro invocation in the tokens of the given file by its expansion,
+    // following the hide set algorithm: each token remembers the macros it comes from,
+    // which may not expand it again
+    pub fn expand(&self, tokens: Vec<PpToken>, file: &str) -> Result<Vec<PpToken>, CompilerError> {
+        let mut input: VecDeque<PpToken> = tokens.into();
+        let mut output = Vec::new();
+        while let Some(token) = input.pop_front() {
+            let name = match token.identifier() {
+                Some(name) if !token.hidden.iter().any(|h| h == name) => name,
+                _ => {
+                    output.push(token);
+                    continue;
+                }
+            };
+            let location = Location {
+                file: file.to_string(),
+                line: token.line,
+                column: token.column,
+            };
+            let mut hidden = token.hidden.clone();
+            let replacement = match self.0.get(name) {
+                Some(m) if m.params.is_none() => {
+                    hidden.push(name.to_string());
+                    self.substitute(m, &[], &token, hidden, file)?
+                }
+                // the name of a function-like macro alone is no invocation
+                Some(m) if input.front().is_some_and(|t| t.is("(")) => {
+                    let (args, close) = arguments(&mut input, m, name, &location)?;
+                    hidden.retain(|h| close.hidden.contains(h));
+                    hidden.push(name.to_string());
+                    self.substitute(m, &args, &token, hidden, file)?
+                }
+                None if name == "__LINE__" => {
+                    vec![PpToken { text: token.line.to_string(), kind: Kind::Number, ..token }]
+                }
+                None if name == "__FILE__" => {
+                    let text = format!("\"{}\"", escape(file));
+                    vec![PpToken { text, kind: Kind::Literal, ..token }]
+                }
+                _ => {
+                    output.push(token);
+                    continue;
+                }
+            };
+            // the expansion is rescanned along with the rest of the input
+            for t in replacement.into_iter().rev() {
+                input.push_front(t);
+            }
+        }
+        Ok(output)
+    }
+
+    // the body of a macro with its parameters replaced, stringized and pasted
+    fn substitute(
+        &self,
+        m: &Macro,
+        args: &[Vec<PpToken>],
+        invocation: &PpToken,
+        hidden: Vec<String>,
+        file: &str,
+    ) -> Result<Vec<PpToken>, CompilerError> {
+        let location = Location {
+            file: file.to_string(),
+            line: invocation.line,
+            column: invocation.column,
+        };
+        let mut output: Vec<PpToken> = Vec::new();
+        let mut i = 0;
+        while i < m.body.len() {
+            let t = &m.body[i];
+            let pasted = m.body.get(i + 1).is_some_and(|next| next.is("##"));
+            if t.is("#") && m.params.is_some() {
+                let arg = &args[m.param(&m.body[i + 1]).unwrap()];
+                output.push(stringize(arg, t));
+                i += 2;
+            } else if t.is("##") {
+                let operand = &m.body[i + 1];
+                let mut right = match m.param(operand) {
+                    Some(p) => args[p].clone(),
+                    None => vec![operand.clone()],
+                };
+                let left = output.pop().unwrap();
+                if right.is_empty() {
+                    output.push(left);
+                } else {
+                    let first = right.remove(0);
+                    output.push(paste(left, first, &location)?);
+                    output.extend(right);
+                }
+                i += 2;
+            } else if let Some(p) = m.param(t) {
+                // arguments are expanded on their own, unless they get pasted
+                let mut arg = if pasted {
+                    args[p].clone()
+                } else {
+                    self.expand(args[p].clone(), file)?
+                };
+                match arg.first_mut() {
+                    Some(first) => first.space = t.space,
+                    None if pasted => {
+                        let placemarker = Kind::Placemarker;
+                        arg.push(PpToken::new(placemarker, String::new(), t.space, 0, 0));
+                    }
+                    None => {}
+                }
+                output.extend(arg);
+                i += 1;
+            } else {
+                output.push(t.clone());
+                i += 1;
+            }
+        }
+
+        output.retain(|t| t.kind != Kind::Placemarker);
+        for (i, t) in output.iter_mut().enumerate() {
+            t.line = invocation.line;
+            t.column = invocation.column;
+            if i == 0 {
+                t.space = invocation.space;
+            }
+            for name in hidden.iter() {
+                if !t.hidden.contains(name) {
+                    t.hidden.push(name.clone());
+                }
+            }
+        }
+        Ok(output)
+    }
+}
+
+// the arguments of a function-like macro invocation and the parenthesis closing them
+fn arguments(
+    input: &mut VecDeque<PpToken>,
+    m: &Macro,
+    name: &str,
+    location: &Location,
+) -> Result<(Vec<Vec<PpToken>>, PpToken), CompilerError> {
+    input.pop_front();
+    let mut args = vec![Vec::new()];
+    let mut depth = 0;
+    let close = loop {
+        let t = input.pop_front().ok_or_else(|| {
+            error(format!("Unterminated argument list invoking macro {}", name), location)
+        })?;
+        if t.is(")") && depth == 0 {
+            break t;
+        } else if t.is(",") && depth == 0 {
+            args.push(Vec::new());
+            continue;
+        } else if t.is("(") {
+            depth += 1;
+        } else if t.is(")") {
+            depth -= 1;
+        }
+        args.last_mut().unwrap().push(t);
+    };
+
+    let expected = m.params.as_ref().map_or(0, Vec::len);
+    // F() passes no argument rather than a single empty one when F takes none
+    if expected == 0 && args.len() == 1 && args[0].is_empty() {
+        args.clear();
+    }
+    if args.len() != expected {
+        return Err(error(
+            format!("Macro {} takes {} arguments but {} were given", name, expected, args.len()),
+            location,
+        ));
+    }
+    Ok((args, close))
+}
+
+// the string literal spelling the argument of a # operator
+fn stringize(arg: &[PpToken], operator: &PpToken) -> PpToken {
+    let mut text = String::from("\"");
+    for (i, t) in arg.iter().enumerate() {
+        if i > 0 && t.space {
+            text.push(' ');
+        }
+        match t.kind {
+            Kind::Literal => text.push_str(&escape(&t.text)),
+            _ => text.push_str(&t.text),
+        }
+    }
+    text.push('"');
+    PpToken::new(Kind::Literal, text, operator.space, operator.line, operator.column)
+}
+
+// the token made of two others stuck together by a ## operator
+fn paste(left: PpToken, right: PpToken, location: &Location) -> Result<PpToken, CompilerError> {
+    if left.kind == Kind::Placemarker {
+        return Ok(PpToken { space: left.space, ..right });
+    }
+    let text = format!("{}{}", left.text, right.text);
+    let mut tokens = tokenize(&text, left.line);
+    if tokens.len() != 1 {
+        return Err(error(
+            format!("Pasting {} and {} does not give a valid token", left.text, right.text),
+            location,
+        ));
+    }
+    let mut token = tokens.remove(0);
+    token.space = left.space;
+    Ok(token)
+}
+
+pub fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn error(message: impl Into<String>, location: &Location) -> CompilerError {
+    CompilerError::Preprocessor(message.into(), location.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn define(macros: &mut Macros, definition: &str) -> Result<(), CompilerError> {
+        macros.define(&tokenize(definition, 1), &Location::default())
+    }
+
+    fn expand(definitions: &[&str], text: &str) -> Result<String, CompilerError> {
+        let mut macros = Macros::default();
+        for definition in definitions {
+            define(&mut macros, definition)?;
+        }
+        let tokens = macros.expand(tokenize(text, 7), "test.c")?;
+        Ok(tokens.iter().map(|t| t.text.as_str()).collect::<Vec<_>>().join(" "))
+    }
+
+    #[test]
+    fn object_like() {
+        assert_eq!(expand(&["N 42"], "N + 1").unwrap(), "42 + 1");
+        assert_eq!(expand(&["EMPTY"], "a EMPTY b").unwrap(), "a b");
+    }
+
+    #[test]
+    fn function_like() {
+        let product = expand(&["F(a, b) a * b"], "F((1, 2), 3)").unwrap();
+        assert_eq!(product, "( 1 , 2 ) * 3");
+        assert_eq!(expand(&["F(a) a"], "F").unwrap(), "F");
+        assert_eq!(expand(&["G() 1"], "G()").unwrap(), "1");
+    }
+
+    #[test]
+    fn expansions_do_not_recurse() {
+        assert_eq!(expand(&["X X + 1"], "X").unwrap(), "X + 1");
+        assert_eq!(expand(&["A B", "B A"], "A").unwrap(), "A");
+        assert_eq!(expand(&["F(x) x F(x)"], "F(F(1))").unwrap(), "1 F ( 1 ) F ( 1 F ( 1 ) )");
+    }
+
+    #[test]
+    fn stringize() {
+        let string = expand(&["S(x) #x"], r#"S(a   "b\n")"#).unwrap();
+        assert_eq!(string, r#""a \"b\\n\"""#);
+    }
+
+    #[test]
+    fn paste() {
+        assert_eq!(expand(&["CAT(a, b) a ## b"], "CAT(x, 1)").unwrap(), "x1");
+        assert_eq!(expand(&["CAT(a, b) a ## b"], "CAT(, y)").unwrap(), "y");
+        assert!(expand(&["CAT(a, b) a ## b"], "CAT(+, /)").is_err());
+    }
+
+    #[test]
+    fn predefined() {
+        assert_eq!(expand(&[], "__LINE__ __FILE__").unwrap(), r#"7 "test.c""#);
+    }
+
+    #[test]
+    fn redefinitions_must_be_identical() {
+        let mut macros = Macros::default();
+        define(&mut macros, "N (1 + 2)").unwrap();
+        assert!(define(&mut macros, "N  (1 + 2)").is_ok());
+        assert!(define(&mut macros, "N (1+2)").is_err());
+        macros.undefine("N");
+        assert!(define(&mut macros, "N 3").is_ok());
+    }
+
+    #[test]
+    fn invalid_definitions() {
+        let mut macros = Macros::default();
+        assert!(define(&mut macros, "defined 1").is_err());
+        assert!(define(&mut macros, "F(a, a) a").is_err());
+        assert!(define(&mut macros, "F(a) ## a").is_err());
+        assert!(define(&mut macros, "F(a) #b").is_err());
+    }
+
+    #[test]
+    fn argument_count_is_checked() {
+        assert!(expand(&["F(a, b) a"], "F(1)").is_err());
+        assert!(expand(&["F(a) a"], "F(1").is_err());
+    }
+}
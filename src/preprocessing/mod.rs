@@ -0,0 +1,608 @@
+mod macros;
+mod tokens;
+
+use self::macros::{escape, Macros};
+use self::tokens::{logical_lines, tokenize, Kind, Line, PpToken};
+use crate::constant::evaluate;
+use crate::error::{CompilerError, Location};
+use crate::lexing::{lex, parse_line_marker};
+use crate::parsing::parse_constant;
+use std::collections::HashSet;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+// as with GCC, guarded files may include themselves so long as some conditional ends it
+// eventually
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+// what the command line asks of the preprocessor
+#[derive(Debug, Default)]
+pub struct Options {
+    pub include_dirs: Vec<PathBuf>,
+    pub macros: Vec<MacroOption>, // in command line order
+}
+
+#[derive(Debug)]
+pub enum MacroOption {
+    Define(String), // NAME, NAME=body or NAME(params)=body
+    Undefine(String),
+}
+
+// turns a source file into the text of its whole translation unit, where lines like
+// `# 12 "file.h"` tell where the lines following them come from
+pub fn preprocess(path: &Path, options: &Options) -> Result<String, CompilerError> {
+    let mut preprocessor = Preprocessor {
+        include_dirs: &options.include_dirs,
+        macros: Macros::default(),
+        stack: Vec::new(),
+        once: HashSet::new(),
+        output: String::new(),
+    };
+
+    let command_line = Location {
+        file: "<command-line>".to_string(),
+        line: 1,
+        column: 1,
+    };
+    let predefined = MacroOption::Define("__STDC__".to_string());
+    for option in std::iter::once(&predefined).chain(options.macros.iter()) {
+        match option {
+            MacroOption::Define(definition) if definition.contains('=') => {
+                let definition = definition.replacen('=', " ", 1);
+                preprocessor.macros.define(&tokenize(&definition, 1), &command_line)?;
+            }
+            MacroOption::Define(name) => {
+                let definition = format!("{} 1", name);
+                preprocessor.macros.define(&tokenize(&definition, 1), &command_line)?;
+            }
+            MacroOption::Undefine(name) => preprocessor.macros.undefine(name),
+        }
+    }
+
+    preprocessor.include(path, None)?;
+    Ok(preprocessor.output)
+}
+
+struct Preprocessor<'a> {
+    include_dirs: &'a [PathBuf],
+    macros: Macros,
+    stack: Vec<(PathBuf, String)>, // canonical paths and names of the files being included
+    once: HashSet<PathBuf>,        // files marked with #pragma once
+    output: String,
+}
+
+// a file being preprocessed
+struct Source {
+    name: String,
+    path: PathBuf,    // canonical
+    dir: PathBuf,     // where the files it includes with quotes are first looked for
+    next_line: usize, // line of the file the next output line is assumed to come from
+    shift: isize,     // from actual line numbers to those given by #line
+}
+
+// an #if, #ifdef or #ifndef along with the #elif and #else that followed so far
+struct Conditional {
+    active: bool, // whether the lines of the current group are kept
+    taken: bool,  // whether a group was kept already, or none may be as the enclosing one isn't
+    after_else: bool,
+    location: Location,
+}
+
+impl<'a> Preprocessor<'a> {
+    // preprocesses the file at path, included from the given location if any, false if it was
+    // skipped for having been included already
+    fn include(&mut self, path: &Path, from: Option<&Location>) -> Result<bool, CompilerError> {
+        let name = path.display().to_string();
+        let location = || {
+            from.cloned().unwrap_or(Location {
+                file: name.clone(),
+                line: 1,
+                column: 1,
+            })
+        };
+        let io_error = |e: std::io::Error| {
+            CompilerError::Preprocessor(format!("{}: {}", name, e), location())
+        };
+        let canonical = path.canonicalize().map_err(io_error)?;
+        if self.once.contains(&canonical) {
+            return Ok(false);
+        }
+        let text = read_to_string(path).map_err(io_error)?;
+        let lines = logical_lines(&text, &name)?;
+        // a file whose include guard is defined has nothing left to give, cycle or not
+        if guard(&lines).is_some_and(|g| self.macros.is_defined(&g)) {
+            return Ok(false);
+        }
+        let cycle = self.stack.iter().rposition(|(p, _)| *p == canonical).map(|i| {
+            let cycle: Vec<_> = self.stack[i..]
+                .iter()
+                .map(|(_, name)| name.as_str())
+                .chain(std::iter::once(name.as_str()))
+                .collect();
+            format!("Include cycle: {}", cycle.join(" -> "))
+        });
+        // nothing stops a file without a guard from including itself again and again
+        let unguarded = guard(&lines).is_none();
+        let message = match cycle {
+            Some(cycle) if unguarded || self.stack.len() >= MAX_INCLUDE_DEPTH => Some(cycle),
+            None if self.stack.len() >= MAX_INCLUDE_DEPTH => {
+                Some(format!("#include nested deeper than {}", MAX_INCLUDE_DEPTH))
+            }
+            _ => None,
+        };
+        if let Some(message) = message {
+            return Err(CompilerError::Preprocessor(message, location()));
+        }
+
+        let mut source = Source {
+            name: name.clone(),
+            path: canonical.clone(),
+            dir: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            next_line: 1,
+            shift: 0,
+        };
+        self.marker(&source, from.map(|_| 1));
+        self.stack.push((canonical, name.clone()));
+        // consecutive text lines are expanded together, as macro arguments may span several
+        let mut text_lines = Vec::new();
+        let mut conditionals = Vec::new();
+        for mut line in lines {
+            line.number = line.number.saturating_add_signed(source.shift);
+            if line.text.trim_start().starts_with('#') {
+                self.expand(&mut source, &mut text_lines)?;
+                self.directive(&mut source, &line, &mut conditionals)?;
+            } else if conditionals.last().is_none_or(|c: &Conditional| c.active) {
+                text_lines.extend(tokenize(&line.text, line.number));
+            }
+        }
+        if let Some(unterminated) = conditionals.pop() {
+            return Err(CompilerError::Preprocessor(
+                "Unterminated conditional directive".to_string(),
+                unterminated.location,
+            ));
+        }
+        self.expand(&mut source, &mut text_lines)?;
+        self.stack.pop();
+        Ok(true)
+    }
+
+    fn directive(
+        &mut self,
+        source: &mut Source,
+        line: &Line,
+        conditionals: &mut Vec<Conditional>,
+    ) -> Result<(), CompilerError> {
+        let tokens = tokenize(&line.text, line.number);
+        let location = Location {
+            file: source.name.clone(),
+            line: line.number,
+            column: tokens[0].column,
+        };
+        let name = match tokens.get(1) {
+            // null directive
+            None => return Ok(()),
+            Some(t) => t.identifier().unwrap_or_default(),
+        };
+        let operands = &tokens[2..];
+        let active = conditionals.last().is_none_or(|c| c.active);
+        let error = |message: &str| {
+            CompilerError::Preprocessor(message.to_string(), location.clone())
+        };
+        match name {
+            "if" | "ifdef" | "ifndef" => {
+                let kept = active && self.condition(name, operands, source, &location)?;
+                conditionals.push(Conditional {
+                    active: kept,
+                    taken: kept || !active,
+                    after_else: false,
+                    location,
+                });
+            }
+            "elif" => {
+                let conditional =
+                    conditionals.last_mut().ok_or_else(|| error("#elif without #if"))?;
+                if conditional.after_else {
+                    return Err(error("#elif after #else"));
+                }
+                // once a group is kept, the conditions of the following ones are not evaluated
+                conditional.active =
+                    !conditional.taken && self.condition("if", operands, source, &location)?;
+                conditional.taken |= conditional.active;
+            }
+            "else" => {
+                let conditional =
+                    conditionals.last_mut().ok_or_else(|| error("#else without #if"))?;
+                if conditional.after_else {
+                    return Err(error("#else after #else"));
+                }
+                conditional.active = !conditional.taken;
+                conditional.taken = true;
+                conditional.after_else = true;
+            }
+            "endif" => {
+                conditionals.pop().ok_or_else(|| error("#endif without #if"))?;
+            }
+            // only conditionals matter in skipped groups, whatever else they hold
+            _ if !active => {}
+            "include" => {
+                let path = self.find_include(operands, source, &location)?;
+                // the rest of the including file follows the directive, nothing else if the
+                // file was skipped
+                if self.include(&path, Some(&location))? {
+                    source.next_line = line.number + line.span;
+                    self.marker(source, Some(2));
+                }
+            }
+            "define" => self.macros.define(operands, &location)?,
+            "undef" => match operands.first().and_then(PpToken::identifier) {
+                Some(name) => self.macros.undefine(name),
+                None => {
+                    return Err(CompilerError::Preprocessor(
+                        "Macro names must be identifiers".to_string(),
+                        location,
+                    ))
+                }
+            },
+            "pragma" if operands.len() == 1 && operands[0].text == "once" => {
+                self.once.insert(source.path.clone());
+            }
+            // other pragmas are not for us
+            "pragma" => {}
+            "line" => {
+                let operands = self.macros.expand(operands.to_vec(), &source.name)?;
+                self.line_marker(source, line, &operands, &location)?;
+            }
+            // as left in the output, so that it can be preprocessed again
+            _ if tokens[1].kind == Kind::Number => {
+                self.line_marker(source, line, &tokens[1..], &location)?;
+            }
+            "error" => return Err(error(&format!("#error {}", spell(operands)))),
+            "warning" => {
+                let message = spell(operands);
+                eprintln!("{}: Preprocessor Warning: #warning {}", location, message);
+            }
+            _ => {
+                return Err(CompilerError::Preprocessor(
+                    format!("Invalid preprocessing directive #{}", tokens[1].text),
+                    location,
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    // the file named by an #include, looked for next to the including file if quoted,
+    // then in the search directories
+    fn find_include(
+        &self,
+        operands: &[PpToken],
+        source: &Source,
+        location: &Location,
+    ) -> Result<PathBuf, CompilerError> {
+        // the file name may come from a macro
+        let operands = match operands.first() {
+            Some(t) if t.text.starts_with('"') || t.is("<") => operands.to_vec(),
+            _ => self.macros.expand(operands.to_vec(), &source.name)?,
+        };
+        let operand = spell(&operands);
+        let delimited = |open, close| operand.strip_prefix(open)?.strip_suffix(close);
+        let (name, quoted) = if let Some(name) = delimited('"', '"') {
+            (name, true)
+        } else if let Some(name) = delimited('<', '>') {
+            (name, false)
+        } else {
+            return Err(CompilerError::Preprocessor(
+                "#include expects \"FILENAME\" or <FILENAME>".to_string(),
+                location.clone(),
+            ));
+        };
+        let current_dir = Some(source.dir.as_path()).filter(|_| quoted);
+        current_dir
+            .into_iter()
+            .chain(self.include_dirs.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                CompilerError::Preprocessor(
+                    format!("{}: No such file or directory", name),
+                    location.clone(),
+                )
+            })
+    }
+
+    // takes the line following a #line or line marker for the given line of the given file,
+    // or of the same file if none is given
+    fn line_marker(
+        &mut self,
+        source: &mut Source,
+        line: &Line,
+        operands: &[PpToken],
+        location: &Location,
+    ) -> Result<(), CompilerError> {
+        let marker = parse_line_marker(&format!("# {}", spell(operands)));
+        let (number, name) = match (marker, operands) {
+            (Some(marker), _) => marker,
+            (None, [number]) if number.text.bytes().all(|c| c.is_ascii_digit()) => {
+                (number.text.parse().unwrap_or(0), source.name.clone())
+            }
+            _ => {
+                return Err(CompilerError::Preprocessor(
+                    "#line expects a line number and an optional file name".to_string(),
+                    location.clone(),
+                ))
+            }
+        };
+        source.shift += number as isize - (line.number + line.span) as isize;
+        source.name = name;
+        source.next_line = number;
+        self.marker(source, None);
+        Ok(())
+    }
+
+    // whether the group following an #if, #ifdef or #ifndef is kept
+    fn condition(
+        &self,
+        directive: &str,
+        operands: &[PpToken],
+        source: &Source,
+        location: &Location,
+    ) -> Result<bool, CompilerError> {
+        let error = |message: String| CompilerError::Preprocessor(message, location.clone());
+        if directive != "if" {
+            return match operands.first().and_then(PpToken::identifier) {
+                Some(name) => Ok(self.macros.is_defined(name) == (directive == "ifdef")),
+                None => Err(error("Macro names must be identifiers".to_string())),
+            };
+        }
+
+        // defined is dealt with before expanding macros, which could make it meaningless
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < operands.len() {
+            let t = &operands[i];
+            if t.identifier() != Some("defined") {
+                tokens.push(t.clone());
+                i += 1;
+                continue;
+            }
+            let parenthesized = operands.get(i + 1).is_some_and(|t| t.is("("));
+            let name = operands.get(i + 1 + parenthesized as usize).and_then(PpToken::identifier);
+            let closed = !parenthesized || operands.get(i + 3).is_some_and(|t| t.is(")"));
+            let Some(name) = name.filter(|_| closed) else {
+                return Err(error("defined expects a macro name".to_string()));
+            };
+            let value = (self.macros.is_defined(name) as usize).to_string();
+            tokens.push(PpToken::new(Kind::Number, value, t.space, t.line, t.column));
+            i += if parenthesized { 4 } else { 2 };
+        }
+        // identifiers left once macros are expanded, keywords included, are 0
+        let mut tokens = self.macros.expand(tokens, &source.name)?;
+        for t in tokens.iter_mut().filter(|t| t.kind == Kind::Identifier) {
+            t.kind = Kind::Number;
+            t.text = "0".to_string();
+        }
+        if tokens.is_empty() {
+            return Err(error("#if with no expression".to_string()));
+        }
+
+        // the compiler's own integer constant expressions are used, with long integers since
+        // #if computes in the widest integer types
+        let text: Vec<_> = tokens.iter().map(widen).collect();
+        let expression = lex(&text.join(" ")).and_then(|tokens| parse_constant(&tokens));
+        match expression {
+            Ok(e) => match evaluate(&e) {
+                Some(value) => Ok(value != 0),
+                None => Err(error("#if expects an integer constant expression".to_string())),
+            },
+            Err(CompilerError::Lexer(e, _)) | Err(CompilerError::Parser(e, _)) => {
+                Err(error(format!("Invalid #if expression: {}", e)))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // expands the pending text lines and outputs them
+    fn expand(
+        &mut self,
+        source: &mut Source,
+        text: &mut Vec<PpToken>,
+    ) -> Result<(), CompilerError> {
+        let tokens = self.macros.expand(std::mem::take(text), &source.name)?;
+        let mut previous: Option<&PpToken> = None;
+        let mut column = 1;
+        for token in tokens.iter() {
+            if previous.is_none_or(|p| p.line != token.line) {
+                if previous.is_some() {
+                    self.output.push('\n');
+                }
+                self.start_line(source, token.line);
+                previous = None;
+                column = 1;
+            }
+            // tokens are put back in their column, unless expansions pushed them further
+            if token.column > column {
+                self.output.push_str(&" ".repeat(token.column - column));
+                column = token.column;
+            } else if previous.is_some_and(|p| token.space || would_merge(p, token)) {
+                self.output.push(' ');
+                column += 1;
+            }
+            self.output.push_str(&token.text);
+            column += token.text.chars().count();
+            previous = Some(token);
+        }
+        if previous.is_some() {
+            self.output.push('\n');
+        }
+        Ok(())
+    }
+
+    // gets the output ready for a line of source with the given number
+    fn start_line(&mut self, source: &mut Source, number: usize) {
+        // small gaps are filled with empty lines, larger ones need a marker
+        if number >= source.next_line && number - source.next_line < 8 {
+            for _ in source.next_line..number {
+                self.output.push('\n');
+            }
+        } else {
+            source.next_line = number;
+            self.marker(source, None);
+        }
+        source.next_line = number + 1;
+    }
+
+    // announces that the next output line is the next line of source, flags 1 and 2 meaning
+    // entering and going back to a file as with GCC
+    fn marker(&mut self, source: &Source, flag: Option<usize>) {
+        self.output.push_str(&format!("# {} \"{}\"", source.next_line, escape(&source.name)));
+        if let Some(flag) = flag {
+            self.output.push_str(&format!(" {}", flag));
+        }
+        self.output.push('\n');
+    }
+}
+
+// the macro of the include guard wrapping a whole file in #ifndef X ... #endif, or in
+// #if !defined X ... #endif, if any
+fn guard(lines: &[Line]) -> Option<String> {
+    let mut lines = lines
+        .iter()
+        .map(|line| tokenize(&line.text, line.number))
+        .filter(|tokens| !tokens.is_empty());
+    let first = lines.next()?;
+    let name = match first.as_slice() {
+        [hash, ifndef, name] if hash.is("#") && ifndef.identifier() == Some("ifndef") => name,
+        [hash, if_, not, defined, rest @ ..]
+            if hash.is("#")
+                && if_.identifier() == Some("if")
+                && not.is("!")
+                && defined.identifier() == Some("defined") =>
+        {
+            match rest {
+                [name] => name,
+                [open, name, close] if open.is("(") && close.is(")") => name,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    }
+    .identifier()?
+    .to_string();
+    let mut depth = 1;
+    for tokens in lines {
+        // something follows the #endif
+        if depth == 0 {
+            return None;
+        }
+        if !tokens[0].is("#") {
+            continue;
+        }
+        match tokens.get(1).and_then(PpToken::identifier) {
+            Some("if") | Some("ifdef") | Some("ifndef") => depth += 1,
+            Some("endif") => depth -= 1,
+            _ => {}
+        }
+    }
+    Some(name).filter(|_| depth == 0)
+}
+
+// the text of a token, with a l suffix added to integer constants that have none
+fn widen(token: &PpToken) -> String {
+    let text = &token.text;
+    let hex = text.starts_with("0x") || text.starts_with("0X");
+    let floating = text.contains('.')
+        || (hex && text.contains(['p', 'P']))
+        || (!hex && text.contains(['e', 'E', 'f', 'F']));
+    if token.kind != Kind::Number || floating || text.contains(['l', 'L']) {
+        text.clone()
+    } else {
+        format!("{}L", text)
+    }
+}
+
+// the text of tokens, spaced as they were
+fn spell(tokens: &[PpToken]) -> String {
+    let mut text = String::new();
+    for t in tokens.iter() {
+        if t.space && !text.is_empty() {
+            text.push(' ');
+        }
+        text.push_str(&t.text);
+    }
+    text
+}
+
+// whether two tokens written side by side would be read back as something else
+fn would_merge(left: &PpToken, right: &PpToken) -> bool {
+    let joined = format!("{}{}", left.text, right.text);
+    let split: Vec<_> = tokenize(&joined, 0).into_iter().map(|t| t.text).collect();
+    split != [left.text.as_str(), right.text.as_str()]
+        || (left.text.ends_with('/') && right.text.starts_with(['/', '*']))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // preprocesses the first of the given files, all written to a directory of their own,
+    // which is left out of the file names in the output or error
+    fn preprocess_files(test: &str, files: &[(&str, &str)]) -> Result<String, String> {
+        let dir = std::env::temp_dir().join(format!("isacc-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, text) in files {
+            std::fs::write(dir.join(name), text).unwrap();
+        }
+        let result = preprocess(&dir.join(files[0].0), &Options::default());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let prefix = format!("{}/", dir.display());
+        match result {
+            Ok(output) => Ok(output.replace(&prefix, "")),
+            Err(CompilerError::Preprocessor(message, _)) => Err(message.replace(&prefix, "")),
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn include_cycles_are_reported_at_once() {
+        let files = [
+            ("a.c", "#include \"b.h\"\n"),
+            ("b.h", "#include \"c.h\"\n"),
+            ("c.h", "#include \"b.h\"\n"),
+        ];
+        assert_eq!(
+            preprocess_files("cycle", &files),
+            Err("Include cycle: b.h -> c.h -> b.h".to_string())
+        );
+    }
+
+    #[test]
+    fn guarded_files_may_include_themselves() {
+        for guard in &["#ifndef G", "#if !defined G", "#if !defined(G)"] {
+            let header = format!("{}\n#define G\n#include \"g.h\"\nint g;\n#endif\n", guard);
+            let files = [("a.c", "#include \"g.h\"\n"), ("g.h", header.as_str())];
+            let output = preprocess_files("guard", &files).unwrap();
+            assert_eq!(output.matches("int g;").count(), 1);
+        }
+    }
+
+    #[test]
+    fn line_markers() {
+        let main = "#include \"g.h\"\n#include \"g.h\"\nint x;\n\n\n\n\n\n\n\n\nint y;\n";
+        let files = [("a.c", main), ("g.h", "#ifndef G\n#define G\nint g;\n#endif\n")];
+        // a skipped include leaves the output as it was, without going back to the includer
+        let expected = concat!(
+            "# 1 \"a.c\"\n# 1 \"g.h\" 1\n\n\nint g;\n# 2 \"a.c\" 2\n",
+            "\nint x;\n# 12 \"a.c\"\nint y;\n",
+        );
+        assert_eq!(preprocess_files("markers", &files).unwrap(), expected);
+    }
+
+    #[test]
+    fn line_directives_renumber_lines() {
+        let files = [("a.c", "int x;\n#line 40 \"z.c\"\nint z;\n#line 7\nint w;\n")];
+        assert_eq!(
+            preprocess_files("line", &files).unwrap(),
+            "# 1 \"a.c\"\nint x;\n# 40 \"z.c\"\nint z;\n# 7 \"z.c\"\nint w;\n"
+        );
+    }
+}
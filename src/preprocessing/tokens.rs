@@ -0,0 +1,295 @@
+use crate::error::{CompilerError, Location};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Identifier,
+    Number,
+    Literal, // string or character
+    Punctuator,
+    Other,
+    Placemarker, // stands for an empty macro argument while pasting
+}
+
+// the preprocessor only splits lines into these, the lexer makes actual tokens out of them
+#[derive(Debug, Clone)]
+pub struct PpToken {
+    pub kind: Kind,
+    pub text: String,
+    pub space: bool, // whether whitespace comes before it
+    pub line: usize,
+    pub column: usize,
+    pub hidden: Vec<String>, // macros it may not invoke anymore, it is "painted blue" for them
+}
+
+impl PpToken {
+    pub fn new(kind: Kind, text: String, space: bool, line: usize, column: usize) -> Self {
+        Self {
+            kind,
+            text,
+            space,
+            line,
+            column,
+            hidden: Vec::new(),
+        }
+    }
+
+    pub fn is(&self, punctuator: &str) -> bool {
+        self.kind == Kind::Punctuator && self.text == punctuator
+    }
+
+    pub fn identifier(&self) -> Option<&str> {
+        match self.kind {
+            Kind::Identifier => Some(&self.text),
+            _ => None,
+        }
+    }
+}
+
+// longest first, so that the first match is the right one
+const PUNCTUATORS: [&str; 23] = [
+    "...", "<<=", ">>=", "##", "->", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+    "*=", "/=", "%=", "+=", "-=", "&=", "^=", "|=",
+];
+
+// splits a line without comments into preprocessing tokens
+pub fn tokenize(text: &str, line: usize) -> Vec<PpToken> {
+    let chars: Vec<char> = text.chars().collect();
+    let word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut tokens = Vec::new();
+    let mut space = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let (start, c) = (i, chars[i]);
+        let next = chars.get(i + 1).copied();
+        i += 1;
+        let kind = if c.is_whitespace() {
+            space = true;
+            continue;
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && word(chars[i]) {
+                i += 1;
+            }
+            Kind::Identifier
+        } else if c.is_ascii_digit() || (c == '.' && next.is_some_and(|c| c.is_ascii_digit())) {
+            // numbers are only split from what follows them, the lexer checks them
+            while i < chars.len() {
+                let exponent = "eEpP".contains(chars[i]) && i + 1 < chars.len();
+                if exponent && "+-".contains(chars[i + 1]) {
+                    i += 2;
+                } else if word(chars[i]) || chars[i] == '.' {
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+            Kind::Number
+        } else if c == '"' || c == '\'' {
+            // an unterminated literal runs to the end of the line, for the lexer to report
+            while i < chars.len() && chars[i] != c {
+                i += if chars[i] == '\\' { 2 } else { 1 };
+            }
+            i = (i + 1).min(chars.len());
+            Kind::Literal
+        } else {
+            let rest: String = chars[start..chars.len().min(start + 3)].iter().collect();
+            match PUNCTUATORS.iter().find(|p| rest.starts_with(*p)) {
+                Some(p) => {
+                    i = start + p.len();
+                    Kind::Punctuator
+                }
+                None if "[](){}.&*+-~!/%<>^|?:;=,#".contains(c) => Kind::Punctuator,
+                None => Kind::Other,
+            }
+        };
+        let text = chars[start..i].iter().collect();
+        tokens.push(PpToken::new(kind, text, space, line, start + 1));
+        space = false;
+    }
+    tokens
+}
+
+// a line once comments and escaped newlines are removed, which may span several physical ones
+pub struct Line {
+    pub number: usize, // of its first physical line
+    pub span: usize,   // number of physical lines
+    pub text: String,
+}
+
+// splits text into lines, joining those ending with a backslash and replacing comments by spaces
+pub fn logical_lines(text: &str, file: &str) -> Result<Vec<Line>, CompilerError> {
+    // escaped newlines are removed before anything else
+    let mut chars = Vec::new();
+    let (mut line, mut column) = (1, 1);
+    let mut iter = text.chars().peekable();
+    while let Some(c) = iter.next() {
+        if c == '\\' && iter.peek() == Some(&'\n') {
+            iter.next();
+            line += 1;
+            column = 1;
+            continue;
+        }
+        chars.push((c, line, column));
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+
+    let mut lines = Vec::new();
+    let mut current = Line {
+        number: 1,
+        span: 1,
+        text: String::new(),
+    };
+    let mut quote = None; // delimiter of the string or character literal we are in
+    let mut i = 0;
+    while i < chars.len() {
+        let (c, line, column) = chars[i];
+        let next = chars.get(i + 1).map(|&(c, _, _)| c);
+        i += 1;
+        match (quote, c, next) {
+            (_, '\n', _) => {
+                quote = None;
+                current.span = line - current.number + 1;
+                let following = Line {
+                    number: line + 1,
+                    span: 1,
+                    text: String::new(),
+                };
+                lines.push(std::mem::replace(&mut current, following));
+            }
+            (Some(_), '\\', Some(escaped)) if escaped != '\n' => {
+                current.text.push(c);
+                current.text.push(escaped);
+                i += 1;
+            }
+            (Some(q), _, _) => {
+                if c == q {
+                    quote = None;
+                }
+                current.text.push(c);
+            }
+            (None, '"', _) | (None, '\'', _) => {
+                quote = Some(c);
+                current.text.push(c);
+            }
+            (None, '/', Some('/')) => {
+                while i < chars.len() && chars[i].0 != '\n' {
+                    i += 1;
+                }
+            }
+            (None, '/', Some('*')) => {
+                i += 1;
+                let end = (i + 1..chars.len())
+                    .find(|&j| chars[j - 1].0 == '*' && chars[j].0 == '/')
+                    .ok_or_else(|| {
+                        CompilerError::Preprocessor(
+                            "Unterminated comment".to_string(),
+                            Location {
+                                file: file.to_string(),
+                                line,
+                                column,
+                            },
+                        )
+                    })?;
+                let (end_line, end_column) = (chars[end].1, chars[end].2);
+                if end_line == line {
+                    // keep columns right when the comment fits on one line
+                    current.text.push_str(&" ".repeat(end - i + 3));
+                } else if current.text.trim_start().starts_with('#') {
+                    current.text.push(' ');
+                } else {
+                    // outside of directives, what follows the comment starts a line of its own
+                    // so that it keeps its line and column
+                    current.span = end_line - current.number;
+                    let following = Line {
+                        number: end_line,
+                        span: 1,
+                        text: " ".repeat(end_column),
+                    };
+                    lines.push(std::mem::replace(&mut current, following));
+                }
+                i = end + 1;
+            }
+            _ => current.text.push(c),
+        }
+    }
+    if !current.text.is_empty() {
+        current.span = line - current.number + 1;
+        lines.push(current);
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(tokens: &[PpToken]) -> Vec<&str> {
+        tokens.iter().map(|t| t.text.as_str()).collect()
+    }
+
+    #[test]
+    fn punctuators_are_longest_first() {
+        assert_eq!(texts(&tokenize("a<<=b...c->d", 1)), ["a", "<<=", "b", "...", "c", "->", "d"]);
+        assert_eq!(texts(&tokenize("x+++y", 1)), ["x", "++", "+", "y"]);
+    }
+
+    #[test]
+    fn numbers_keep_exponent_signs() {
+        assert_eq!(texts(&tokenize("1e+5+x", 1)), ["1e+5", "+", "x"]);
+        assert_eq!(texts(&tokenize("0x1p-3 .5f 12uL", 1)), ["0x1p-3", ".5f", "12uL"]);
+    }
+
+    #[test]
+    fn literals_run_to_their_closing_quote() {
+        let tokens = tokenize(r#"s = "a\"b" 'c'"#, 1);
+        assert_eq!(texts(&tokens), ["s", "=", r#""a\"b""#, "'c'"]);
+        assert_eq!(tokens[2].kind, Kind::Literal);
+    }
+
+    #[test]
+    fn tokens_know_their_column_and_spacing() {
+        let tokens = tokenize("  x  y(z", 4);
+        let positions: Vec<_> = tokens.iter().map(|t| (t.line, t.column, t.space)).collect();
+        assert_eq!(positions, [(4, 3, true), (4, 6, true), (4, 7, false), (4, 8, false)]);
+    }
+
+    #[test]
+    fn escaped_newlines_join_lines() {
+        let lines = logical_lines("a \\\nb\nc\n", "test.c").unwrap();
+        let lines: Vec<_> = lines.iter().map(|l| (l.number, l.span, l.text.as_str())).collect();
+        assert_eq!(lines, [(1, 2, "a b"), (3, 1, "c")]);
+    }
+
+    #[test]
+    fn comments_keep_columns() {
+        let lines = logical_lines("a /* x */ b // c\n", "test.c").unwrap();
+        let tokens = tokenize(&lines[0].text, lines[0].number);
+        assert_eq!(texts(&tokens), ["a", "b"]);
+        assert_eq!(tokens[1].column, 11);
+    }
+
+    #[test]
+    fn text_after_multi_line_comment_keeps_its_line() {
+        let lines = logical_lines("x /* a\nb */ y\n", "test.c").unwrap();
+        assert_eq!(lines.len(), 2);
+        let tokens = tokenize(&lines[1].text, lines[1].number);
+        assert_eq!((tokens[0].line, tokens[0].column), (2, 6));
+    }
+
+    #[test]
+    fn directives_stay_on_one_line() {
+        let lines = logical_lines("#define X /* a\n */ 1\n", "test.c").unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].span, 2);
+        assert_eq!(texts(&tokenize(&lines[0].text, 1)), ["#", "define", "X", "1"]);
+    }
+
+    #[test]
+    fn unterminated_comment_is_an_error() {
+        assert!(logical_lines("int x; /* never\nends", "test.c").is_err());
+    }
+}
@@ -0,0 +1,561 @@
+use crate::ast::*;
+use crate::constant::evaluate;
+use crate::error::{CompilerError, Span};
+use crate::layout::Layouts;
+use std::collections::{HashMap, HashSet};
+
+// return type, argument types and whether more arguments may follow
+pub type Signature = (Type, Vec<Type>, bool);
+
+pub trait TypeEnv {
+    fn variable_type(&self, id: &Identifier) -> Option<Type>;
+    fn function_type(&self, id: &Identifier) -> Option<Signature>;
+    fn enclosing_function(&self) -> Option<&Identifier>;
+    fn layouts(&self) -> &Layouts;
+}
+
+impl Function {
+    pub fn signature(&self) -> Signature {
+        // qualifiers on parameters only concern the function body
+        let args_types = self.args.iter().map(|a| a.0.unqualified().clone()).collect();
+        (self.return_type.clone(), args_types, self.variadic)
+    }
+}
+
+// variables and functions visible at some point of the program
+pub struct Scope {
+    pub functions: HashMap<Identifier, Signature>,
+    pub variables: Vec<HashMap<Identifier, Type>>,
+    // local variables declared static or extern, along with the depth they were declared at
+    statics: HashSet<(usize, Identifier)>,
+    pub function: Option<Identifier>,
+    pub layouts: Layouts,
+}
+
+impl Scope {
+    pub fn new(layouts: Layouts) -> Self {
+        Self {
+            functions: HashMap::new(),
+            variables: vec![HashMap::new()],
+            statics: HashSet::new(),
+            function: None,
+            layouts,
+        }
+    }
+
+    pub fn declare(
+        &mut self,
+        id: &Identifier,
+        t: &Type,
+        span: &Span,
+    ) -> Result<(), CompilerError> {
+        if !self.layouts.is_complete(t) {
+            return Err(error(format!("Variable {} has incomplete type", id), span));
+        }
+        let scope = self.variables.last_mut().unwrap();
+        if scope.insert(id.clone(), t.clone()).is_some() {
+            return Err(error(format!("Redeclaration of variable {}", id), span));
+        }
+        self.statics.remove(&(self.variables.len() - 1, id.clone()));
+        Ok(())
+    }
+
+    pub fn declare_static(
+        &mut self,
+        id: &Identifier,
+        t: &Type,
+        span: &Span,
+    ) -> Result<(), CompilerError> {
+        self.declare(id, t, span)?;
+        self.statics.insert((self.variables.len() - 1, id.clone()));
+        Ok(())
+    }
+
+    // whether id names a variable or function living for the whole program
+    pub fn has_static_storage(&self, id: &Identifier) -> bool {
+        match self.variables.iter().rposition(|scope| scope.contains_key(id)) {
+            Some(0) => true,
+            Some(depth) => self.statics.contains(&(depth, id.clone())),
+            None => self.functions.contains_key(id),
+        }
+    }
+}
+
+impl TypeEnv for Scope {
+    fn variable_type(&self, id: &Identifier) -> Option<Type> {
+        self.variables
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(id))
+            .cloned()
+    }
+
+    fn function_type(&self, id: &Identifier) -> Option<Signature> {
+        self.functions.get(id).cloned()
+    }
+
+    fn enclosing_function(&self) -> Option<&Identifier> {
+        self.function.as_ref()
+    }
+
+    fn layouts(&self) -> &Layouts {
+        &self.layouts
+    }
+}
+
+impl Expression {
+    pub fn is_lvalue(&self) -> bool {
+        match &self.kind {
+            ExpressionKind::Identifier(_)
+            | ExpressionKind::Deref(_)
+            | ExpressionKind::StringLiteral(_) => true,
+            ExpressionKind::Member(e, _) => e.is_lvalue(),
+            _ => false,
+        }
+    }
+
+    pub fn is_null_pointer_constant(&self) -> bool {
+        evaluate(self) == Some(0)
+    }
+
+    // type of the value of the expression, after array to pointer decay
+    // values are never qualified, only the objects they are read from
+    pub fn typ(&self, env: &impl TypeEnv) -> Result<Type, CompilerError> {
+        Ok(self.object_type(env)?.decay().unqualified().clone())
+    }
+
+    // type of the expression itself, arrays are kept as is
+    pub fn object_type(&self, env: &impl TypeEnv) -> Result<Type, CompilerError> {
+        match &self.kind {
+            ExpressionKind::Identifier(id) => env
+                .variable_type(id)
+                .or_else(|| {
+                    env.function_type(id).map(|(return_type, arg_types, variadic)| {
+                        Type::Function(Box::new(return_type), arg_types, variadic)
+                    })
+                })
+                .ok_or_else(|| error(format!("Undeclared variable {}", id), &self.span)),
+            ExpressionKind::Literal(_, t) | ExpressionKind::FloatLiteral(_, t) => Ok(t.clone()),
+            ExpressionKind::StringLiteral(s) => {
+                Ok(Type::Array(Box::new(Type::Char), Length::Constant(s.len() + 1)))
+            },
+            ExpressionKind::Minus(e) => {
+                let t = e.typ(env)?;
+                if !t.is_arithmetic() {
+                    return Err(error("Invalid operand to unary operator", &self.span));
+                }
+                Ok(t.promote())
+            }
+            ExpressionKind::BinaryNot(e) => {
+                let t = e.typ(env)?;
+                if !t.is_integer() {
+                    return Err(error("Invalid operand to unary operator", &self.span));
+                }
+                Ok(t.promote())
+            }
+            ExpressionKind::LogicalNot(e) => {
+                scalar(e, env)?;
+                Ok(Type::Int)
+            }
+            ExpressionKind::AddressOf(e) => {
+                if !e.is_lvalue() {
+                    return Err(error("Cannot take the address of an rvalue", &self.span));
+                }
+                Ok(Type::Pointer(Box::new(e.object_type(env)?)))
+            }
+            ExpressionKind::Deref(e) => match e.typ(env)? {
+                Type::Pointer(t) => Ok(*t),
+                _ => Err(error("Dereferencing a non-pointer value", &self.span)),
+            },
+            ExpressionKind::Cast(t, e) => {
+                let from = e.typ(env)?;
+                match t.unqualified() {
+                    Type::Void => Ok(Type::Void),
+                    t if (t.is_floating() && from.is_pointer())
+                        || (t.is_pointer() && from.is_floating()) =>
+                    {
+                        let message = "Cannot convert between pointer and floating point types";
+                        Err(error(message, &self.span))
+                    }
+                    t if t.is_scalar() && from.is_scalar() => Ok(t.clone()),
+                    t if t.is_scalar() => Err(error("Cannot cast a non-scalar value", &self.span)),
+                    _ => Err(error("Conversion to non-scalar type requested", &self.span)),
+                }
+            }
+            // members of a qualified struct share its qualifiers
+            ExpressionKind::Member(e, id) => {
+                let t = e.object_type(env)?;
+                match t.unqualified() {
+                    Type::Struct(name) => env
+                        .layouts()
+                        .get(name)
+                        .ok_or_else(|| {
+                            error(format!("Use of incomplete struct {}", name), &self.span)
+                        })?
+                        .fields
+                        .get(id)
+                        .map(|(field, _)| field.clone().qualify(t.qualifiers()))
+                        .ok_or_else(|| {
+                            error(format!("Struct {} has no member named {}", name, id), &self.span)
+                        }),
+                    _ => {
+                        let message =
+                            format!("Request for member {} in something not a struct", id);
+                        Err(error(message, &self.span))
+                    }
+                }
+            }
+            ExpressionKind::PreIncrement(e)
+            | ExpressionKind::PreDecrement(e)
+            | ExpressionKind::PostIncrement(e)
+            | ExpressionKind::PostDecrement(e) => {
+                let t = assignable(e, env, "Increment or decrement")?;
+                if !t.is_scalar() {
+                    return Err(error("Invalid operand to increment or decrement", &self.span));
+                }
+                stepped(t.unqualified().clone(), env, &self.span)
+            }
+            ExpressionKind::Add(e1, e2) => match (e1.typ(env)?, e2.typ(env)?) {
+                (t1, t2) if t1.is_arithmetic() && t2.is_arithmetic() => {
+                    Ok(arithmetic_type(&t1, &t2, env.layouts()))
+                }
+                (t1, t2) if t1.is_pointer() && t2.is_integer() => stepped(t1, env, &self.span),
+                (t1, t2) if t1.is_integer() && t2.is_pointer() => stepped(t2, env, &self.span),
+                _ => Err(error("Invalid operands to +", &self.span)),
+            },
+            ExpressionKind::Subtract(e1, e2) => match (e1.typ(env)?, e2.typ(env)?) {
+                (t1, t2) if t1.is_arithmetic() && t2.is_arithmetic() => {
+                    Ok(arithmetic_type(&t1, &t2, env.layouts()))
+                }
+                (t1, t2) if t1.is_pointer() && t2.is_integer() => stepped(t1, env, &self.span),
+                // ptrdiff_t
+                (t1, t2) if t1.is_pointer() && same_pointee(&t1, &t2) => {
+                    stepped(t1, env, &self.span).map(|_| Type::Long)
+                }
+                _ => Err(error("Invalid operands to -", &self.span)),
+            },
+            ExpressionKind::Multiply(e1, e2) | ExpressionKind::Divide(e1, e2) => {
+                match (e1.typ(env)?, e2.typ(env)?) {
+                    (t1, t2) if t1.is_arithmetic() && t2.is_arithmetic() => {
+                        Ok(arithmetic_type(&t1, &t2, env.layouts()))
+                    }
+                    _ => Err(error("Invalid operands to arithmetic operator", &self.span)),
+                }
+            }
+            ExpressionKind::Modulo(e1, e2)
+            | ExpressionKind::BitwiseAnd(e1, e2)
+            | ExpressionKind::BitwiseOr(e1, e2)
+            | ExpressionKind::BitwiseXor(e1, e2) => {
+                match (e1.typ(env)?, e2.typ(env)?) {
+                    (t1, t2) if t1.is_integer() && t2.is_integer() => {
+                        Ok(arithmetic_type(&t1, &t2, env.layouts()))
+                    }
+                    _ => Err(error("Invalid operands to arithmetic operator", &self.span)),
+                }
+            }
+            // the type of a shift is that of its promoted left operand
+            ExpressionKind::ShiftLeft(e1, e2) | ExpressionKind::ShiftRight(e1, e2) => {
+                match (e1.typ(env)?, e2.typ(env)?) {
+                    (t1, t2) if t1.is_integer() && t2.is_integer() => Ok(t1.promote()),
+                    _ => Err(error("Invalid operands to shift operator", &self.span)),
+                }
+            }
+            ExpressionKind::And(e1, e2) | ExpressionKind::Or(e1, e2) => {
+                scalar(e1, env)?;
+                scalar(e2, env)?;
+                Ok(Type::Int)
+            }
+            ExpressionKind::Equal(e1, e2)
+            | ExpressionKind::NotEqual(e1, e2)
+            | ExpressionKind::LessThan(e1, e2)
+            | ExpressionKind::LessThanOrEqual(e1, e2)
+            | ExpressionKind::GreaterThan(e1, e2)
+            | ExpressionKind::GreaterThanOrEqual(e1, e2) => {
+                let (t1, t2) = (scalar(e1, env)?, scalar(e2, env)?);
+                if !(compatible(&t1, &t2, e2) || compatible(&t2, &t1, e1)) {
+                    return Err(error("Comparison between incompatible types", &self.span));
+                }
+                Ok(Type::Int)
+            }
+            ExpressionKind::Assignment(lhs, rhs) => {
+                let t = assignable(lhs, env, "Assignment")?;
+                if !compatible(&t, &rhs.typ(env)?, rhs) {
+                    return Err(error("Incompatible types in assignment", &self.span));
+                }
+                Ok(t.unqualified().clone())
+            }
+            // typed as lhs = lhs op rhs
+            ExpressionKind::CompoundAssignment(op, lhs, rhs) => {
+                let t = assignable(lhs, env, "Assignment")?;
+                let value = op.operation()(lhs.clone(), rhs.clone());
+                let value = Expression::new(value, self.span.clone());
+                if !compatible(&t, &value.typ(env)?, &value) {
+                    return Err(error("Incompatible types in assignment", &self.span));
+                }
+                Ok(t.unqualified().clone())
+            }
+            ExpressionKind::Comma(e1, e2) => {
+                e1.typ(env)?;
+                e2.typ(env)
+            }
+            ExpressionKind::Conditional(cond, exp, alt) => {
+                scalar(cond, env)?;
+                let (t1, t2) = (exp.typ(env)?, alt.typ(env)?);
+                if t1.is_arithmetic() && t2.is_arithmetic() {
+                    Ok(arithmetic_type(&t1, &t2, env.layouts()))
+                } else if compatible(&t1, &t2, alt) {
+                    Ok(t1)
+                } else if compatible(&t2, &t1, exp) {
+                    Ok(t2)
+                } else {
+                    Err(error("Mismatched types in conditional expression", &self.span))
+                }
+            }
+            ExpressionKind::FunCall(callee, args) => {
+                let id = match &callee.kind {
+                    ExpressionKind::Identifier(id) => {
+                        if env.variable_type(id).is_none() && env.function_type(id).is_none() {
+                            return Err(error(format!("Undeclared function {}", id), &callee.span));
+                        }
+                        id.as_str()
+                    }
+                    _ => "function pointer",
+                };
+                let (return_type, arg_types, variadic) = match callee.typ(env)?.pointee() {
+                    Some(Type::Function(return_type, arg_types, variadic)) => {
+                        ((**return_type).clone(), arg_types.clone(), *variadic)
+                    }
+                    _ => {
+                        let message = format!("Called object {} is not a function", id);
+                        return Err(error(message, &self.span));
+                    }
+                };
+                if args.len() < arg_types.len() || (!variadic && args.len() > arg_types.len()) {
+                    return Err(error(format!("Wrong number of arguments to {}", id), &self.span));
+                }
+                for (t, arg) in arg_types.iter().zip(args.iter()) {
+                    if !compatible(t, &arg.typ(env)?, arg) {
+                        let message = format!("Incompatible argument type in call to {}", id);
+                        return Err(error(message, &arg.span));
+                    }
+                }
+                // only the fixed arguments can be checked, the others must merely be passable
+                for arg in args.iter().skip(arg_types.len()) {
+                    if !arg.typ(env)?.is_scalar() {
+                        let message = format!("Invalid variadic argument in call to {}", id);
+                        return Err(error(message, &arg.span));
+                    }
+                }
+                Ok(return_type)
+            }
+            ExpressionKind::VaStart(ap) => {
+                va_list(ap, env)?;
+                let variadic = env
+                    .enclosing_function()
+                    .and_then(|f| env.function_type(f))
+                    .is_some_and(|(_, _, variadic)| variadic);
+                if !variadic {
+                    return Err(error("va_start used in function with fixed arguments", &self.span));
+                }
+                Ok(Type::Void)
+            }
+            ExpressionKind::VaArg(ap, t) => {
+                va_list(ap, env)?;
+                if !t.is_scalar() {
+                    return Err(error("va_arg only supports scalar types", &self.span));
+                }
+                Ok(t.clone())
+            }
+            // size_t
+            ExpressionKind::SizeOf(t) | ExpressionKind::AlignOf(t) => {
+                complete(t, env, &self.span)?;
+                Ok(Type::ULong)
+            }
+            ExpressionKind::SizeOfExpr(e) => {
+                complete(&e.object_type(env)?, env, &self.span)?;
+                Ok(Type::ULong)
+            }
+        }
+    }
+}
+
+// whether a value of type `from` computed by `expr` can be stored into a `to`
+// pointers may gain qualifiers on the way but never lose any
+pub fn compatible(to: &Type, from: &Type, expr: &Expression) -> bool {
+    let (to, from) = (to.unqualified(), from.unqualified());
+    let keeps_qualifiers = || match (to.pointee(), from.pointee()) {
+        (Some(t), Some(f)) => t.qualifiers().contains(f.qualifiers()),
+        _ => false,
+    };
+    to == from
+        || (to.is_arithmetic() && from.is_arithmetic())
+        || (to.is_pointer() && from.is_integer() && expr.is_null_pointer_constant())
+        || (same_pointee(to, from) && keeps_qualifiers())
+        || ((to.is_void_pointer() || from.is_void_pointer()) && keeps_qualifiers())
+}
+
+// whether both types point to the same type, whatever its qualifiers
+fn same_pointee(t1: &Type, t2: &Type) -> bool {
+    match (t1.pointee(), t2.pointee()) {
+        (Some(t1), Some(t2)) => t1.unqualified() == t2.unqualified(),
+        _ => false,
+    }
+}
+
+// common type of the operands of a binary operator under the usual arithmetic conversions
+pub fn arithmetic_type(t1: &Type, t2: &Type, layouts: &Layouts) -> Type {
+    if *t1 == Type::Double || *t2 == Type::Double {
+        return Type::Double;
+    } else if *t1 == Type::Float || *t2 == Type::Float {
+        return Type::Float;
+    }
+
+    let (t1, t2) = (t1.clone().promote(), t2.clone().promote());
+    let (high, low) = if t1.rank() >= t2.rank() { (t1, t2) } else { (t2, t1) };
+    if high.is_unsigned() || !low.is_unsigned() {
+        high
+    } else if layouts.size(&high) > layouts.size(&low) {
+        // the signed type can represent every value of the unsigned one
+        high
+    } else {
+        high.to_unsigned()
+    }
+}
+
+// the type of the object modified by operation through e
+fn assignable(e: &Expression, env: &impl TypeEnv, operation: &str) -> Result<Type, CompilerError> {
+    let t = e.object_type(env)?;
+    if !e.is_lvalue() || t.is_array() || t.is_function() {
+        return Err(error("Expression is not assignable", &e.span));
+    }
+    if t.qualifiers().constant {
+        let message = match &e.kind {
+            ExpressionKind::Identifier(id) => format!("{} of read-only variable {}", operation, id),
+            _ => format!("{} of read-only location", operation),
+        };
+        return Err(error(message, &e.span));
+    }
+    Ok(t)
+}
+
+// pointer arithmetic steps over whole objects, so they must be complete
+// void and functions count as single bytes, as in GNU C
+fn stepped(t: Type, env: &impl TypeEnv, span: &Span) -> Result<Type, CompilerError> {
+    match t.pointee().map(Type::unqualified) {
+        Some(Type::Void) | Some(Type::Function(_, _, _)) | None => Ok(t),
+        Some(pointee) if env.layouts().is_complete(pointee) => Ok(t),
+        Some(_) => Err(error("Arithmetic on a pointer to an incomplete type", span)),
+    }
+}
+
+fn complete(t: &Type, env: &impl TypeEnv, span: &Span) -> Result<(), CompilerError> {
+    if !env.layouts().is_complete(t) {
+        let message = "Invalid application of sizeof or _Alignof to an incomplete type";
+        return Err(error(message, span));
+    }
+    Ok(())
+}
+
+fn va_list(e: &Expression, env: &impl TypeEnv) -> Result<(), CompilerError> {
+    if e.typ(env)? != Type::va_list().decay() {
+        return Err(error("Expected a va_list", &e.span));
+    }
+    Ok(())
+}
+
+fn scalar(e: &Expression, env: &impl TypeEnv) -> Result<Type, CompilerError> {
+    let t = e.typ(env)?;
+    if !t.is_scalar() {
+        return Err(error("Expected a scalar value", &e.span));
+    }
+    Ok(t)
+}
+
+fn error(message: impl Into<String>, span: &Span) -> CompilerError {
+    CompilerError::Validation(message.into(), span.location.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexing::lex;
+    use crate::parsing::{parse, parse_constant};
+
+    const DECLARATIONS: &str = "struct P { int x; long y; };
+        struct Q;
+        struct P s;
+        const struct P cs;
+        struct P *ps;
+        struct Q *q;
+        void *v;
+        int i;
+        char c;
+        short sh;
+        unsigned u;
+        long l;
+        unsigned long ul;";
+
+    fn typ(expression: &str) -> Result<Type, CompilerError> {
+        let program = parse(&lex(DECLARATIONS).unwrap()).unwrap();
+        let mut scope = Scope::new(Layouts::new(&program.structs)?);
+        for global in program.globals.iter() {
+            scope.variables[0].insert(global.name.clone(), global.typename.clone());
+        }
+        parse_constant(&lex(expression).unwrap()).unwrap().typ(&scope)
+    }
+
+    fn pointer(t: Type) -> Type {
+        Type::Pointer(Box::new(t))
+    }
+
+    #[test]
+    fn members() {
+        assert_eq!(typ("s.y").unwrap(), Type::Long);
+        assert_eq!(typ("ps->x").unwrap(), Type::Int);
+        let constant = Qualifiers {
+            constant: true,
+            volatile: false,
+        };
+        assert_eq!(typ("&cs.x").unwrap(), pointer(Type::Int.qualify(constant)));
+        assert!(typ("s.z").is_err());
+        assert!(typ("i.x").is_err());
+        assert!(typ("q->x").is_err());
+    }
+
+    #[test]
+    fn pointer_arithmetic() {
+        let p = pointer(Type::Struct("P".to_string()));
+        assert_eq!(typ("ps + 1").unwrap(), p);
+        assert_eq!(typ("2 + ps").unwrap(), p);
+        assert_eq!(typ("ps - ps").unwrap(), Type::Long);
+        assert_eq!(typ("v + 1").unwrap(), pointer(Type::Void));
+        assert!(typ("ps + ps").is_err());
+        assert!(typ("ps * 2").is_err());
+    }
+
+    #[test]
+    fn pointers_to_incomplete_structs_cannot_be_stepped() {
+        for expression in ["q + 1", "1 + q", "q - 1", "q - q", "q++", "--q", "q[1]"] {
+            assert!(typ(expression).is_err(), "{}", expression);
+        }
+        assert!(typ("q == 0").is_ok());
+    }
+
+    #[test]
+    fn usual_arithmetic_conversions() {
+        assert_eq!(typ("c + sh").unwrap(), Type::Int);
+        assert_eq!(typ("-c").unwrap(), Type::Int);
+        assert_eq!(typ("~u").unwrap(), Type::UInt);
+        assert_eq!(typ("i * u").unwrap(), Type::UInt);
+        assert_eq!(typ("u + l").unwrap(), Type::Long);
+        assert_eq!(typ("l % ul").unwrap(), Type::ULong);
+        assert_eq!(typ("c ? sh : l").unwrap(), Type::Long);
+        // shifts take the type of their left operand alone, comparisons are ints
+        assert_eq!(typ("c << l").unwrap(), Type::Int);
+        assert_eq!(typ("ul >> i").unwrap(), Type::ULong);
+        assert_eq!(typ("i < u").unwrap(), Type::Int);
+        // assignments keep the type of what is assigned to
+        assert_eq!(typ("(c = l)").unwrap(), Type::Char);
+        assert_eq!(typ("(sh += ul)").unwrap(), Type::Short);
+    }
+}
@@ -1,104 +1,534 @@
 use crate::ast::*;
-use crate::error::CompilerError;
-use std::collections::HashMap;
+use crate::constant::{evaluate, evaluate_address, evaluate_as};
+use crate::error::{CompilerError, Span};
+use crate::layout::Layouts;
+use crate::typing::{compatible, Scope, Signature};
+use std::collections::{HashMap, HashSet};
 
 pub fn validate(program: &Program) -> Result<(), CompilerError> {
     conflicting_function_definitions(program)?;
-    undeclared_function_call(program)?;
+    conflicting_global_definitions(program)?;
+    invalid_control_flow(program)?;
+    invalid_goto_targets(program)?;
+    type_check(program)?;
     Ok(())
 }
 
-fn validate_all_expr(
-    statement: &Statement,
-    f: &dyn Fn(&Expression) -> Result<(), CompilerError>,
+fn conflicting_function_definitions(program: &Program) -> Result<(), CompilerError> {
+    let mut fun_map: HashMap<&Identifier, (Signature, bool)> = HashMap::new();
+    for fun in program.funs.iter() {
+        let signature = fun.signature();
+        let prev = fun_map.get(&fun.name);
+        if prev.is_some_and(|(prev, _)| *prev != signature) {
+            let message = format!("Conflicting definitions for function {}", fun.name);
+            return Err(error(message, &fun.span));
+        }
+        let internal = prev.map(|p| p.1);
+        check_linkage(&fun.name, internal, fun.storage, false, &fun.span)?;
+
+        let internal = internal.unwrap_or(fun.storage == Some(StorageClass::Static));
+        fun_map.insert(&fun.name, (signature, internal));
+    }
+    Ok(())
+}
+
+// the first declaration of a global identifier decides whether it is visible to other
+// translation units, the following ones may not contradict it
+fn check_linkage(
+    name: &Identifier,
+    internal: Option<bool>,
+    storage: Option<StorageClass>,
+    is_object: bool,
+    span: &Span,
 ) -> Result<(), CompilerError> {
-    match statement {
-        Statement::Declaration(_, _, expr) => expr.as_ref().map_or(Ok(()), f),
-        Statement::Return(expr) => f(expr),
-        Statement::Expression(expr) => expr.as_ref().map_or(Ok(()), f),
-        Statement::If(expr, stm1, stm2) => {
-            f(expr)?;
-            validate_all_expr(stm1, f)?;
-            stm2.as_ref().map_or(Ok(()), |s| validate_all_expr(s, f))
-        }
-        Statement::Compound(stms) => {
-            for s in stms.iter() {
-                validate_all_expr(s, f)?;
+    match (internal, storage) {
+        (Some(false), Some(StorageClass::Static)) => Err(error(
+            format!("Static declaration of {} follows non-static declaration", name),
+            span,
+        )),
+        // functions declared without storage class behave as if declared extern
+        (Some(true), None) if is_object => Err(error(
+            format!("Non-static declaration of {} follows static declaration", name),
+            span,
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn conflicting_global_definitions(program: &Program) -> Result<(), CompilerError> {
+    let mut global_map: HashMap<&Identifier, (&Type, bool, bool)> = HashMap::new();
+    for Global {
+        name,
+        typename,
+        value,
+        storage,
+        span,
+    } in program.globals.iter()
+    {
+        if program.funs.iter().any(|f| &f.name == name) {
+            let message = format!("{} redeclared as a different kind of symbol", name);
+            return Err(error(message, span));
+        }
+        let defined = value.is_some();
+        if let Some((prev_type, prev_defined, _)) = global_map.get(name) {
+            if *prev_type != typename {
+                let message = format!("Conflicting types for global variable {}", name);
+                return Err(error(message, span));
+            }
+            if *prev_defined && defined {
+                return Err(error(format!("Redefinition of global variable {}", name), span));
             }
-            Ok(())
         }
-        Statement::For(expr1, expr2, expr3, stm) => {
-            expr1.as_ref().map_or(Ok(()), f)?;
-            f(expr2)?;
-            expr3.as_ref().map_or(Ok(()), f)?;
-            validate_all_expr(stm, f)
+
+        let internal = global_map.get(name).map(|g| g.2);
+        check_linkage(name, internal, *storage, true, span)?;
+
+        let defined = defined || global_map.get(name).is_some_and(|g| g.1);
+        let internal = internal.unwrap_or(*storage == Some(StorageClass::Static));
+        global_map.insert(name, (typename, defined, internal));
+    }
+    Ok(())
+}
+
+fn type_check(program: &Program) -> Result<(), CompilerError> {
+    let mut scope = Scope::new(Layouts::new(&program.structs)?);
+    for global in program.globals.iter() {
+        if !scope.layouts.is_complete(&global.typename) {
+            let message = format!("Global variable {} has incomplete type", global.name);
+            return Err(error(message, &global.span));
         }
-        Statement::ForDecl(stm1, expr1, expr2, stm2) => {
-            f(expr1)?;
-            expr2.as_ref().map_or(Ok(()), f)?;
-            validate_all_expr(stm1, f)?;
-            validate_all_expr(stm2, f)
+        scope.variables[0].insert(global.name.clone(), global.typename.clone());
+    }
+
+    // initializers may take the address of any function
+    for fun in program.funs.iter() {
+        scope.functions.insert(fun.name.clone(), fun.signature());
+    }
+    for global in program.globals.iter() {
+        if let Some(value) = &global.value {
+            if !compatible(&global.typename, &value.typ(&scope)?, value) {
+                let message =
+                    format!("Incompatible initializer for global variable {}", global.name);
+                return Err(error(message, &value.span));
+            }
+            if !is_static_initializer(value, &global.typename, &scope) {
+                let message =
+                    format!("Initializer of global variable {} is not constant", global.name);
+                return Err(error(message, &value.span));
+            }
         }
-        Statement::While(expr, stm) => {
-            validate_all_expr(stm, f)?;
-            f(expr)
+    }
+    scope.functions.clear();
+
+    for fun in program.funs.iter() {
+        let mut by_value = std::iter::once(&fun.return_type)
+            .chain(fun.args.iter().map(|a| a.0.unqualified()));
+        if let Some(Type::Struct(name)) = by_value.find(|t| matches!(t, Type::Struct(_))) {
+            let message = format!("Passing struct {} by value is not supported", name);
+            return Err(error(message, &fun.span));
         }
-        Statement::Do(stm, expr) => {
-            validate_all_expr(stm, f)?;
-            f(expr)
+
+        scope.functions.insert(fun.name.clone(), fun.signature());
+        scope.function = Some(fun.name.clone());
+
+        if let Some(statements) = &fun.statements {
+            scope.variables.push(HashMap::new());
+            for (t, id) in fun.args.iter() {
+                scope.declare(id, t, &fun.span)?;
+            }
+            for stm in statements.iter() {
+                type_check_statement(stm, &mut scope, &fun.return_type)?;
+            }
+            scope.variables.pop();
         }
-        Statement::Break | Statement::Continue => Ok(()),
     }
+    Ok(())
 }
 
-fn undeclared_function_call(program: &Program) -> Result<(), CompilerError> {
-    let mut fun_map: HashMap<&Identifier, Vec<Type>> = HashMap::new();
-    for Function {
-        name,
-        args,
-        statements,
-    } in program.funs.iter()
-    {
-        let args_types: Vec<Type> = args.iter().map(|a| a.0.clone()).collect();
-        fun_map.insert(name, args_types);
-
-        for stm in statements.as_ref().unwrap_or(&Vec::new()).iter() {
-            validate_all_expr(stm, &|e| {
-                if let Expression::FunCall(id, args) = e {
-                    // TODO: typecheck args
-                    if !(fun_map.contains_key(&id) && fun_map.get(&id).unwrap().len() == args.len())
-                    {
-                        return Err(CompilerError::Validation(format!(
-                            "Undeclared function {}",
+// objects living for the whole program are initialized before it runs, with a constant or the
+// address of another such object
+fn is_static_initializer(expr: &Expression, t: &Type, scope: &Scope) -> bool {
+    let symbol = |id: &Identifier| Some(id.clone()).filter(|id| scope.has_static_storage(id));
+    evaluate_as(expr, t).is_some()
+        || matches!(expr.kind, ExpressionKind::StringLiteral(_))
+        || evaluate_address(expr, scope, &symbol).is_some()
+}
+
+fn type_check_statement(
+    statement: &Statement,
+    scope: &mut Scope,
+    return_type: &Type,
+) -> Result<(), CompilerError> {
+    let condition = |expr: &Expression, scope: &Scope| {
+        if expr.typ(scope)?.is_scalar() {
+            Ok(())
+        } else {
+            Err(error("Condition must have scalar type", &expr.span))
+        }
+    };
+
+    match &statement.kind {
+        StatementKind::Declaration(t, id, expr, storage) => {
+            match storage {
+                Some(_) => scope.declare_static(id, t, &statement.span)?,
+                None => scope.declare(id, t, &statement.span)?,
+            }
+            match (storage, expr) {
+                (Some(StorageClass::Extern), Some(_)) => {
+                    let message = format!("Local extern variable {} cannot be initialized", id);
+                    return Err(error(message, &statement.span));
+                }
+                (Some(StorageClass::Static), Some(expr))
+                    if !is_static_initializer(expr, t, scope) =>
+                {
+                    let message = format!("Initializer of static variable {} is not constant", id);
+                    return Err(error(message, &expr.span));
+                }
+                _ => {}
+            }
+            if let Some(expr) = expr {
+                if !compatible(t, &expr.typ(scope)?, expr) {
+                    let message = format!("Incompatible initializer for variable {}", id);
+                    return Err(error(message, &expr.span));
+                }
+            }
+        }
+        StatementKind::Return(expr) => match (return_type, expr) {
+            (Type::Void, Some(_)) => {
+                return Err(error("Returning a value from a void function", &statement.span))
+            }
+            (Type::Void, None) => {}
+            (_, None) => {
+                return Err(error("Missing return value in non-void function", &statement.span))
+            }
+            (_, Some(expr)) => {
+                if !compatible(return_type, &expr.typ(scope)?, expr) {
+                    return Err(error("Incompatible type in return statement", &expr.span));
+                }
+            }
+        },
+        StatementKind::Expression(expr) => {
+            if let Some(expr) = expr {
+                expr.typ(scope)?;
+            }
+        }
+        StatementKind::If(cond, stm1, stm2) => {
+            condition(cond, scope)?;
+            type_check_statement(stm1, scope, return_type)?;
+            if let Some(stm2) = stm2 {
+                type_check_statement(stm2, scope, return_type)?;
+            }
+        }
+        StatementKind::Compound(stms) => {
+            scope.variables.push(HashMap::new());
+            for stm in stms.iter() {
+                type_check_statement(stm, scope, return_type)?;
+            }
+            scope.variables.pop();
+        }
+        StatementKind::For(init, cond, iter, body) => {
+            if let Some(init) = init {
+                init.typ(scope)?;
+            }
+            condition(cond, scope)?;
+            if let Some(iter) = iter {
+                iter.typ(scope)?;
+            }
+            type_check_statement(body, scope, return_type)?;
+        }
+        StatementKind::ForDecl(decl, cond, iter, body) => {
+            scope.variables.push(HashMap::new());
+            for stm in decl.iter() {
+                if let StatementKind::Declaration(_, id, _, Some(_)) = &stm.kind {
+                    return Err(error(
+                        format!(
+                            "Variable {} declared in a for loop initializer must be automatic",
                             id
-                        )));
-                    }
+                        ),
+                        &stm.span,
+                    ));
                 }
-                Ok(())
-            })?;
+                type_check_statement(stm, scope, return_type)?;
+            }
+            condition(cond, scope)?;
+            if let Some(iter) = iter {
+                iter.typ(scope)?;
+            }
+            type_check_statement(body, scope, return_type)?;
+            scope.variables.pop();
+        }
+        StatementKind::While(cond, body) | StatementKind::Do(body, cond) => {
+            condition(cond, scope)?;
+            type_check_statement(body, scope, return_type)?;
         }
+        StatementKind::Switch(cond, body) => {
+            if !cond.typ(scope)?.is_integer() {
+                return Err(error("Switch quantity is not an integer", &cond.span));
+            }
+            type_check_statement(body, scope, return_type)?;
+        }
+        StatementKind::Case(value, stm) => {
+            if !value.typ(scope)?.is_integer() {
+                return Err(error("Case label is not an integer", &value.span));
+            }
+            type_check_statement(stm, scope, return_type)?;
+        }
+        StatementKind::Default(stm) | StatementKind::Labeled(_, stm) => {
+            type_check_statement(stm, scope, return_type)?
+        }
+        StatementKind::Break | StatementKind::Continue | StatementKind::Goto(_) => {}
     }
     Ok(())
 }
 
-fn conflicting_function_definitions(program: &Program) -> Result<(), CompilerError> {
-    let mut fun_map: HashMap<&Identifier, Vec<Type>> = HashMap::new();
-    for Function {
-        name,
-        args,
-        statements: _,
-    } in program.funs.iter()
-    {
-        let args_types: Vec<Type> = args.iter().map(|a| a.0.clone()).collect();
+#[derive(Default)]
+struct SwitchCases {
+    values: HashSet<i64>,
+    default: bool,
+}
 
-        if fun_map.contains_key(name) && !fun_map.get(name).unwrap().iter().eq(args_types.iter()) {
-            return Err(CompilerError::Validation(format!(
-                "Conflicting definitions for function {}",
-                name
-            )));
+fn invalid_control_flow(program: &Program) -> Result<(), CompilerError> {
+    for fun in program.funs.iter() {
+        for stm in fun.statements.iter().flatten() {
+            control_flow(stm, false, false, None)?;
         }
+    }
+    Ok(())
+}
 
-        fun_map.insert(name, args_types);
+fn control_flow(
+    statement: &Statement,
+    in_loop: bool,
+    can_break: bool,
+    mut switch: Option<&mut SwitchCases>,
+) -> Result<(), CompilerError> {
+    match &statement.kind {
+        StatementKind::Break if !can_break => {
+            Err(error("Break statement not within a loop or switch", &statement.span))
+        }
+        StatementKind::Continue if !in_loop => {
+            Err(error("Continue statement not within a loop", &statement.span))
+        }
+        StatementKind::Case(label, stm) => {
+            let cases = switch.ok_or_else(|| {
+                error("Case label not within a switch statement", &statement.span)
+            })?;
+            let value = evaluate(label).ok_or_else(|| {
+                error("Case label is not an integer constant", &label.span)
+            })?;
+            if !cases.values.insert(value) {
+                return Err(error(format!("Duplicate case value {}", value), &label.span));
+            }
+            control_flow(stm, in_loop, can_break, Some(cases))
+        }
+        StatementKind::Default(stm) => {
+            let cases = switch.ok_or_else(|| {
+                error("Default label not within a switch statement", &statement.span)
+            })?;
+            if cases.default {
+                return Err(error("Multiple default labels in one switch", &statement.span));
+            }
+            cases.default = true;
+            control_flow(stm, in_loop, can_break, Some(cases))
+        }
+        StatementKind::Switch(_, body) => {
+            control_flow(body, in_loop, true, Some(&mut SwitchCases::default()))
+        }
+        StatementKind::Labeled(_, stm) => control_flow(stm, in_loop, can_break, switch),
+        StatementKind::For(_, _, _, body)
+        | StatementKind::ForDecl(_, _, _, body)
+        | StatementKind::While(_, body)
+        | StatementKind::Do(body, _) => control_flow(body, true, true, switch),
+        StatementKind::If(_, stm, alt) => {
+            control_flow(stm, in_loop, can_break, switch.as_deref_mut())?;
+            match alt {
+                Some(alt) => control_flow(alt, in_loop, can_break, switch),
+                None => Ok(()),
+            }
+        }
+        StatementKind::Compound(stms) => {
+            for stm in stms.iter() {
+                control_flow(stm, in_loop, can_break, switch.as_deref_mut())?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn invalid_goto_targets(program: &Program) -> Result<(), CompilerError> {
+    for fun in program.funs.iter() {
+        let mut labels = HashSet::new();
+        let mut gotos = Vec::new();
+        for stm in fun.statements.iter().flatten() {
+            collect_labels(stm, &mut labels, &mut gotos)?;
+        }
+        if let Some(goto) = gotos.into_iter().find(|g| !labels.contains(g.0)) {
+            let message =
+                format!("Label {} used but not defined in function {}", goto.0, fun.name);
+            return Err(error(message, goto.1));
+        }
     }
     Ok(())
 }
+
+fn collect_labels<'a>(
+    statement: &'a Statement,
+    labels: &mut HashSet<&'a Identifier>,
+    gotos: &mut Vec<(&'a Identifier, &'a Span)>,
+) -> Result<(), CompilerError> {
+    match &statement.kind {
+        StatementKind::Labeled(label, stm) => {
+            if !labels.insert(label) {
+                return Err(error(format!("Duplicate label {}", label), &statement.span));
+            }
+            collect_labels(stm, labels, gotos)
+        }
+        StatementKind::Goto(label) => {
+            gotos.push((label, &statement.span));
+            Ok(())
+        }
+        StatementKind::If(_, stm, alt) => {
+            collect_labels(stm, labels, gotos)?;
+            match alt {
+                Some(alt) => collect_labels(alt, labels, gotos),
+                None => Ok(()),
+            }
+        }
+        StatementKind::Compound(stms) => {
+            for stm in stms.iter() {
+                collect_labels(stm, labels, gotos)?;
+            }
+            Ok(())
+        }
+        StatementKind::For(_, _, _, stm)
+        | StatementKind::ForDecl(_, _, _, stm)
+        | StatementKind::While(_, stm)
+        | StatementKind::Do(stm, _)
+        | StatementKind::Switch(_, stm)
+        | StatementKind::Case(_, stm)
+        | StatementKind::Default(stm) => collect_labels(stm, labels, gotos),
+        _ => Ok(()),
+    }
+}
+
+fn error(message: impl Into<String>, span: &Span) -> CompilerError {
+    CompilerError::Validation(message.into(), span.location.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexing::lex;
+    use crate::lowering::lower;
+    use crate::parsing::parse;
+
+    // the message of the first validation error in a program, if any
+    fn check(source: &str) -> Result<(), String> {
+        let mut program = parse(&lex(source).unwrap()).unwrap();
+        lower(&mut program).unwrap();
+        validate(&program).map_err(|e| match e {
+            CompilerError::Validation(message, _) => message,
+            e => panic!("unexpected error {:?}", e),
+        })
+    }
+
+    #[test]
+    fn linkage_may_not_change() {
+        assert_eq!(
+            check("int x; static int x;"),
+            Err("Static declaration of x follows non-static declaration".to_string())
+        );
+        assert_eq!(
+            check("static int x; int x;"),
+            Err("Non-static declaration of x follows static declaration".to_string())
+        );
+        assert!(check("int f(); static int f();").is_err());
+        // extern declarations and functions without storage class keep the first linkage
+        assert_eq!(check("static int x; extern int x;"), Ok(()));
+        assert_eq!(check("static int f(); int f() { return 0; }"), Ok(()));
+    }
+
+    #[test]
+    fn static_initializers_are_constant() {
+        assert_eq!(check("int g[4]; int *p = &g[2]; int *q = g + 1;"), Ok(()));
+        assert_eq!(check("int f(); int (*fp)() = f;"), Ok(()));
+        assert_eq!(check("int f() { static int n; static int *p = &n; return 0; }"), Ok(()));
+        assert_eq!(
+            check("int x; int y = x;"),
+            Err("Initializer of global variable y is not constant".to_string())
+        );
+        assert_eq!(
+            check("int f() { int l; static int *p = &l; return 0; }"),
+            Err("Initializer of static variable p is not constant".to_string())
+        );
+    }
+
+    #[test]
+    fn switch_labels() {
+        let switch = |body: &str| {
+            check(&format!("int f(int x) {{ switch (x) {{ {} }} return 0; }}", body))
+        };
+        assert_eq!(switch("case 1: case 2: default: break;"), Ok(()));
+        // nested switches have cases of their own
+        assert_eq!(switch("case 1: switch (x) { case 1: break; }"), Ok(()));
+        assert_eq!(switch("case 1: case 3 - 2: break;"), Err("Duplicate case value 1".to_string()));
+        assert_eq!(
+            switch("default: default: break;"),
+            Err("Multiple default labels in one switch".to_string())
+        );
+        assert_eq!(
+            switch("case x: break;"),
+            Err("Case label is not an integer constant".to_string())
+        );
+        assert_eq!(
+            switch("case 1: continue;"),
+            Err("Continue statement not within a loop".to_string())
+        );
+        assert_eq!(
+            check("int f() { case 1: return 0; }"),
+            Err("Case label not within a switch statement".to_string())
+        );
+        assert_eq!(
+            check("int f(int *p) { switch (p) { } return 0; }"),
+            Err("Switch quantity is not an integer".to_string())
+        );
+    }
+
+    #[test]
+    fn goto_targets() {
+        // labels are visible in the whole function, before or after the goto
+        assert_eq!(check("int f() { goto end; { end: ; } again: goto again; }"), Ok(()));
+        assert_eq!(
+            check("int f() { goto end; } int g() { end: return 0; }"),
+            Err("Label end used but not defined in function f".to_string())
+        );
+        assert_eq!(
+            check("int f() { a: ; if (1) a: ; return 0; }"),
+            Err("Duplicate label a".to_string())
+        );
+    }
+
+    #[test]
+    fn void_values_cannot_be_used() {
+        assert_eq!(check("void f() { return; } int g() { (void)1; f(); return 0; }"), Ok(()));
+        assert_eq!(
+            check("void f() { return 1; }"),
+            Err("Returning a value from a void function".to_string())
+        );
+        assert_eq!(
+            check("int f() { return; }"),
+            Err("Missing return value in non-void function".to_string())
+        );
+        assert_eq!(check("void x;"), Err("Global variable x has incomplete type".to_string()));
+        assert_eq!(
+            check("void f() {} int g() { return f() + 1; }"),
+            Err("Invalid operands to +".to_string())
+        );
+        assert_eq!(
+            check("void f() {} int g() { if (f()) return 1; return 0; }"),
+            Err("Condition must have scalar type".to_string())
+        );
+        assert!(check("int g(void *p) { return *p; }").is_err());
+    }
+}